@@ -0,0 +1,187 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use crate::AppData;
+use crate::hydra::HydraOAuthClient;
+use crate::scope::{PROTOCOL_SCOPES, SCOPE_SEPARATOR};
+use crate::system::{System, SystemStore};
+use crate::authenticator::{AuthenticatorStore, AuthenticatorType};
+use crate::entity::{EntityError, EntityStore};
+use crate::lockout::ThrottleStore;
+use serde_json::json;
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientReq {
+    client_name: String,
+    redirect_uris: Vec<String>,
+    grant_types: Option<Vec<String>>,
+    response_types: Option<Vec<String>>,
+    token_endpoint_auth_method: Option<String>
+}
+
+// Admin routes are closed unless an admin token is configured and presented as a bearer token
+pub fn authorize(req: &HttpRequest, data: &AppData<'_>) -> Result<(), Error> {
+    let expected = data.admin_token.as_ref().ok_or(error::ErrorForbidden("The admin API is disabled"))?;
+    let presented = req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("Bearer "))
+        .map(|v| &v["Bearer ".len()..]);
+    // Compared in constant time so response timing does not reveal how much of the token matched
+    match presented {
+        Some(token) if token.len() == expected.len() && openssl::memcmp::eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(error::ErrorUnauthorized("A valid admin bearer token is required"))
+    }
+}
+
+fn find_system(guid: &str) -> Result<System, Error> {
+    SystemStore::find_by_guid(guid, vec![
+        "uid".to_string(),
+        "guid".to_string(),
+        "client_id".to_string(),
+        "namespace { name scope { name } }".to_string()
+    ]).map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorNotFound("System with guid does not exist"))
+}
+
+fn bound_client(system: &System, client_id: &str) -> Result<(), Error> {
+    if system.client_ids.clone().unwrap_or_default().iter().any(|c| c == client_id) {
+        return Ok(())
+    }
+    Err(error::ErrorNotFound("OAuth client is not bound to this system"))
+}
+
+// Every Scope of every Namespace on the System becomes an allowed namespace:scope OAuth scope
+fn system_scope(system: &System) -> String {
+    let mut scopes: Vec<String> = PROTOCOL_SCOPES.iter().map(|s| s.to_string()).collect();
+    for ns in system.namespaces.clone().unwrap_or_default() {
+        let ns_name = match ns.name {
+            Some(name) => name,
+            None => continue
+        };
+        for scope in ns.scopes.unwrap_or_default() {
+            if let Some(name) = scope.name {
+                scopes.push(format!("{}{}{}", ns_name, SCOPE_SEPARATOR, name));
+            }
+        }
+    }
+    scopes.join(" ")
+}
+
+fn hydra_client(system: &System, client_id: String, item: ClientReq) -> HydraOAuthClient {
+    HydraOAuthClient {
+        client_id,
+        client_name: Some(item.client_name),
+        redirect_uris: Some(item.redirect_uris),
+        grant_types: Some(item.grant_types.unwrap_or_else(|| vec!["authorization_code".to_string(), "refresh_token".to_string()])),
+        response_types: Some(item.response_types.unwrap_or_else(|| vec!["code".to_string(), "id_token".to_string()])),
+        token_endpoint_auth_method: item.token_endpoint_auth_method,
+        scope: Some(system_scope(system)),
+        owner: system.guid.clone(),
+        ..Default::default()
+    }
+}
+
+pub async fn create_client(path: web::Path<String>, item: web::Json<ClientReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+    let system = find_system(&path)?;
+
+    let client = hydra_client(&system, nanoid::nanoid!(), item.into_inner());
+    let created = data.hydra.create_client(&client).await?;
+
+    // A client no System owns could not log anyone in nor be managed here, so it is removed again
+    if let Err(e) = SystemStore::associate_client_id(&path, &created.client_id, vec!["uid".to_string()]) {
+        if let Err(cleanup) = data.hydra.delete_client(&created.client_id).await {
+            eprintln!("Could not delete unassociated client {}: {}", created.client_id, cleanup);
+        }
+        return Err(error::ErrorInternalServerError(e))
+    }
+
+    Ok(HttpResponse::Created().json(created))
+}
+
+pub async fn list_clients(path: web::Path<String>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+    let system = find_system(&path)?;
+
+    let mut clients: Vec<HydraOAuthClient> = vec![];
+    for client_id in system.client_ids.unwrap_or_default() {
+        clients.push(data.hydra.get_client(&client_id).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(clients))
+}
+
+pub async fn update_client(path: web::Path<(String, String)>, item: web::Json<ClientReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+    let (guid, client_id) = path.into_inner();
+    let system = find_system(&guid)?;
+    bound_client(&system, &client_id)?;
+
+    let client = hydra_client(&system, client_id.clone(), item.into_inner());
+    let updated = data.hydra.update_client(&client_id, &client).await?;
+
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+pub async fn delete_client(path: web::Path<(String, String)>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+    let (guid, client_id) = path.into_inner();
+    let system = find_system(&guid)?;
+    bound_client(&system, &client_id)?;
+
+    data.hydra.delete_client(&client_id).await?;
+    SystemStore::dissociate_client_id(&guid, &client_id, vec!["uid".to_string()])
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Lifts a lockout and resets the failure counters on every authenticator of the entity
+pub async fn unlock_entity(path: web::Path<String>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+
+    let unlocked = match AuthenticatorStore::unlock_entity(&path) {
+        Ok(unlocked) => unlocked,
+        Err(ref e) if e.downcast_ref::<EntityError>().is_some() => return Err(error::ErrorNotFound("Entity with guid does not exist")),
+        Err(e) => return Err(error::ErrorInternalServerError(e))
+    };
+
+    Ok(HttpResponse::Ok().json(json!({ "unlocked_authenticators": unlocked })))
+}
+
+// Unused recovery codes of the entity, in total and per system guid. The codes themselves cannot be read back.
+pub async fn recovery_code_count(path: web::Path<String>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+
+    if EntityStore::find_by_guid(&path, vec!["uid".to_string()]).map_err(error::ErrorInternalServerError)?.is_none() {
+        return Err(error::ErrorNotFound("Entity with guid does not exist"))
+    }
+
+    let codes = AuthenticatorStore::find_by_entity_type(&AuthenticatorType::recovery_code, &path, vec!["uid".to_string(), "system { guid }".to_string()])
+        .map_err(error::ErrorInternalServerError)?;
+    let mut systems: HashMap<String, usize> = HashMap::new();
+    for code in codes.iter() {
+        for system in code.systems.clone().unwrap_or_default() {
+            if let Some(guid) = system.guid {
+                *systems.entry(guid).or_insert(0) += 1;
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "remaining": codes.len(),
+        "systems": systems
+    })))
+}
+
+pub async fn clear_ip_throttle(path: web::Path<String>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    authorize(&req, &data)?;
+
+    let cleared = ThrottleStore::clear(&ThrottleStore::ip_key(&path)).map_err(error::ErrorInternalServerError)?;
+    if !cleared {
+        return Err(error::ErrorNotFound("No failed logins are recorded for this address"))
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
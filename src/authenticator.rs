@@ -8,6 +8,13 @@ use failure_derive::*;
 use crate::entity::{Entity, EntityStore, EntityError};
 use crate::identifier::{Identifier, IdentifierStore, IdentifierType, IdentifierError};
 use crate::system::{System, SystemStore};
+use crate::totp;
+use crate::public_key;
+use crate::webauthn::{self, AssertionResponse, StoredCredential};
+use crate::lockout::{self, FailureState, ThrottleStore, Verdict};
+use crate::token::{TokenPurpose, TokenStore};
+use crate::password;
+use crate::recovery;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AuthenticatorRoot {
@@ -26,6 +33,10 @@ pub enum AuthenticatorError {
     Empty(),
     #[fail(display = "Authenticator with uid does not exist")]
     DoesNotExist(),
+    #[fail(display = "The account this authenticator belongs to is locked")]
+    Locked(),
+    #[fail(display = "Too many failed attempts, retry in {} seconds", _0)]
+    Throttled(i64),
 }
 
 impl From<String> for AuthenticatorError {
@@ -39,8 +50,26 @@ pub enum AuthenticatorType {
     username_password,
     phone_password,
     email_password,
-    public_key_authentication
+    public_key_authentication,
+    totp,
+    webauthn,
+    // Passwordless login by a link mailed to the email identifier; the value is unused
+    email_link,
+    // Passwordless login by a code texted to the phone identifier; the value is unused
+    phone_otp,
+    // Single-use stand-in for the second factor, the value is an argon2 hash of the code
+    recovery_code
+}
 
+impl AuthenticatorType {
+    pub fn password_for(identifier_type: &IdentifierType) -> AuthenticatorType {
+        match identifier_type {
+            IdentifierType::email => AuthenticatorType::email_password,
+            IdentifierType::phone => AuthenticatorType::phone_password,
+            IdentifierType::username => AuthenticatorType::username_password,
+            IdentifierType::public_key => AuthenticatorType::public_key_authentication
+        }
+    }
 }
 
 impl Display for AuthenticatorType {
@@ -61,6 +90,11 @@ pub struct Authenticator {
     pub systems: Option<Vec<System>>,
     pub authenticator_type: Option<AuthenticatorType>,
     pub value: Option<String>,
+    pub failed_attempts: Option<i32>,
+    pub last_failed_at: Option<i64>,
+    pub locked_until: Option<i64>,
+    // Time step of the last TOTP code accepted, so the same code cannot be replayed
+    pub last_used_step: Option<i64>,
     #[serde(rename = "dgraph.type")]
     pub dtype: Option<Vec<String>>
 }
@@ -118,6 +152,22 @@ impl Authenticator {
         self
     }
 
+    pub fn last_used_step(mut self, step: i64) -> Self {
+        self.last_used_step = Some(step);
+        self
+    }
+
+    pub fn failure_state(&self) -> FailureState {
+        FailureState::new(self.failed_attempts, self.last_failed_at, self.locked_until)
+    }
+
+    pub fn with_failure_state(mut self, state: &FailureState) -> Self {
+        self.failed_attempts = Some(state.failed_attempts);
+        self.last_failed_at = Some(state.last_failed_at);
+        self.locked_until = Some(state.locked_until);
+        self
+    }
+
     pub fn validate(&mut self) -> bool {
         if self.entities.is_none() || self.identifiers.is_none() {
             return false
@@ -144,7 +194,17 @@ impl AuthenticatorStore {
             },
             AuthenticatorType::username_password => {
                 ident_type == &IdentifierType::username
-            }
+            },
+            AuthenticatorType::email_link => {
+                ident_type == &IdentifierType::email
+            },
+            AuthenticatorType::phone_otp => {
+                ident_type == &IdentifierType::phone
+            },
+            // Second factors belong to the entity, whichever identifier they were enrolled through
+            AuthenticatorType::totp
+            | AuthenticatorType::webauthn
+            | AuthenticatorType::recovery_code => true
         }
     }
 
@@ -191,12 +251,20 @@ impl AuthenticatorStore {
                      "identifier_type".to_string()])?.ok_or(AuthenticatorError::Empty()
             )?
         }
-        let exists = Self::find_by_type_identifier(
+        let create_auth_ident_uid = create_auth_ident.uid.clone().ok_or(AuthenticatorError::Empty())?;
+        let create_auth_system = a.clone().systems
+            .ok_or(AuthenticatorError::Empty())?
+            .get(0)
+            .ok_or(AuthenticatorError::Empty())?
+            .guid.clone().ok_or(AuthenticatorError::Empty())?;
+        let exists = Self::find_by_type_identifier_system(
             &create_auth_type,
-            &create_auth_ident,
+            &create_auth_ident_uid,
+            &create_auth_system,
             vec!["uid".to_string()]
         )?;
-        if exists.is_some() {
+        // An identifier may register several passkeys, one per device, and holds a whole batch of recovery codes
+        if exists.is_some() && create_auth_type != AuthenticatorType::webauthn && create_auth_type != AuthenticatorType::recovery_code {
             return Err(AuthenticatorError::AuthenticatorExists().into())
         }
         if a.clone().validate() {
@@ -224,7 +292,7 @@ impl AuthenticatorStore {
                                                          .ok_or(AuthenticatorError::Empty())?
                                                          .uid.as_ref().ok_or(AuthenticatorError::Empty())?
                                                      , ass.clone(), vec!["uid".to_string()]);
-            return Self::find_by_type_identifier(&create_auth_type, &create_auth_ident, fields)
+            return Self::find_by_type_identifier_system(&create_auth_type, &create_auth_ident_uid, &create_auth_system, fields)
         }
         Err(AuthenticatorError::AuthenticatorExists().into())
     }
@@ -251,42 +319,283 @@ impl AuthenticatorStore {
         return Self::find_by_uid(uid, fields);
     }
 
-    pub fn find_by_type_identifier(authenticator_type: &AuthenticatorType, i: &Identifier, fields: Vec<String>) -> Result<Option<Authenticator>, failure::Error> {
-        if i.uid.is_none() {
-            return Err(AuthenticatorError::EmptyField("uid".to_string()).into())
+    // An identifier holds one authenticator of a type per System, which is the one _find_for_login picks,
+    // so the same type on another System is no duplicate
+    pub fn find_by_type_identifier_system(authenticator_type: &AuthenticatorType, identifier_uid: &str, system_guid: &str, fields: Vec<String>) -> Result<Option<Authenticator>, failure::Error> {
+        let mut query_fields = fields;
+        if !query_fields.iter().any(|f| f == "system { guid }") {
+            query_fields.push("system { guid }".to_string());
         }
+        let found = Self::find_by_identifier_uid_type(identifier_uid, authenticator_type, query_fields)?;
+        Ok(Self::_on_system(found, system_guid))
+    }
+
+    fn _on_system(authenticators: Vec<Authenticator>, system_guid: &str) -> Option<Authenticator> {
+        authenticators.into_iter().find(|a| {
+            a.systems.as_ref()
+                .map(|systems| systems.iter().any(|s| s.guid.as_deref() == Some(system_guid)))
+                .unwrap_or(false)
+        })
+    }
+
+    // Every authenticator of a type hanging off the identifier, whichever system it belongs to
+    pub fn find_by_identifier_uid_type(identifier_uid: &str, authenticator_type: &AuthenticatorType, fields: Vec<String>) -> Result<Vec<Authenticator>, failure::Error> {
         let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
             handlebars::Handlebars::new()
         });
         let req: &'static str = r#"
-        query authenticator($type: string, $uid: string) {
-			    authenticator(func: eq(authenticator_type, $type)) @filter(eq(dgraph.type, "Authenticator")) {
-			        {{#each fields }}
-			          {{this}}
-			        {{/each}}
-			        _: identifier @filter(uid($uid)) {
-			            uid
-			        }
+            query authenticator($uid: string, $type: string) {
+                identifier(func: uid($uid)) @filter(eq(dgraph.type, "Identifier")) {
+                    A as authenticator @filter(eq(authenticator_type, $type))
+                }
+
+                authenticator(func: uid(A)) {
+                    {{#each fields }}
+                        {{this}}
+                    {{/each}}
+                }
 			}
-		}
         "#;
         let template_vars = &json!({
             "fields": fields
         });
         let query = reg.render_template(req, template_vars)?;
-        let mut vars: HashMap<String, String> = [
-            ("$type".to_string(), authenticator_type.to_string()),
-            ("$uid".to_string(), format!("{}", i.uid.as_ref().ok_or(AuthenticatorError::Empty())?))
+        let vars: HashMap<String, String> = [
+            ("$uid".to_string(), identifier_uid.to_string()),
+            ("$type".to_string(), authenticator_type.to_string())
         ].iter().cloned().collect();
         let res = db::query(query, vars)?;
         let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
-        match e.authenticator.len() {
-            0 => Ok(None),
-            _ => Ok(Some(e.authenticator.get(0).ok_or(AuthenticatorError::Empty())?.clone()))
+        Ok(e.authenticator)
+    }
+
+    // Authenticator types an identifier can log in with on a system, used to pick the second login step
+    pub fn find_types_by_identifier_system(i: &Identifier, s: &System) -> Result<Vec<AuthenticatorType>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query authenticator($ident_type: string, $ident_value: string, $sys_guid: string) {
+                identifier(func: eq(identifier_type, $ident_type)) @filter(eq(value, $ident_value)) {
+                    A as authenticator
+                }
+
+                authenticator(func: uid(A)) @cascade {
+                    authenticator_type
+                    system @filter(eq(guid, $sys_guid)) {
+                        uid
+                    }
+                }
+			}
+        "#;
+        let template_vars = &json!({});
+        let query = reg.render_template(req, template_vars)?;
+        let vars: HashMap<String, String> = [
+            ("$ident_type".to_string(), format!("{}", i.identifier_type.as_ref().ok_or(AuthenticatorError::Empty())?)),
+            ("$ident_value".to_string(), format!("{}", i.value.as_ref().ok_or(AuthenticatorError::Empty())?)),
+            ("$sys_guid".to_string(), format!("{}", s.guid.as_ref().ok_or(AuthenticatorError::Empty())?))
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
+        let mut types: Vec<AuthenticatorType> = vec![];
+        for a in e.authenticator {
+            if let Some(t) = a.authenticator_type {
+                if !types.contains(&t) {
+                    types.push(t);
+                }
+            }
         }
+        Ok(types)
     }
 
-    pub fn login(a: Authenticator, i: Identifier, s: System) -> Result<bool, failure::Error> {
+    pub fn find_by_entity_system(authenticator_type: &AuthenticatorType, entity_guid: &str, system_guid: &str, fields: Vec<String>) -> Result<Vec<Authenticator>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query authenticator($auth_type: string, $guid: string, $sys_guid: string) {
+                entity(func: eq(guid, $guid)) @filter(eq(dgraph.type, "Entity")) {
+                    A as authenticator @filter(eq(authenticator_type, $auth_type))
+                }
+
+                authenticator(func: uid(A)) @cascade {
+                    {{#each fields }}
+                        {{this}}
+                    {{/each}}
+                    system @filter(eq(guid, $sys_guid)) {
+                        uid
+                    }
+                }
+			}
+        "#;
+        let template_vars = &json!({
+            "fields": fields
+        });
+        let query = reg.render_template(req, template_vars)?;
+        let vars: HashMap<String, String> = [
+            ("$auth_type".to_string(), authenticator_type.to_string()),
+            ("$guid".to_string(), entity_guid.to_string()),
+            ("$sys_guid".to_string(), system_guid.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
+        Ok(e.authenticator)
+    }
+
+    // Every authenticator of the type held by the entity, on any system
+    pub fn find_by_entity_type(authenticator_type: &AuthenticatorType, entity_guid: &str, fields: Vec<String>) -> Result<Vec<Authenticator>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query authenticator($auth_type: string, $guid: string) {
+                entity(func: eq(guid, $guid)) @filter(eq(dgraph.type, "Entity")) {
+                    A as authenticator @filter(eq(authenticator_type, $auth_type))
+                }
+
+                authenticator(func: uid(A)) {
+                    {{#each fields }}
+                        {{this}}
+                    {{/each}}
+                }
+			}
+        "#;
+        let template_vars = &json!({
+            "fields": fields
+        });
+        let query = reg.render_template(req, template_vars)?;
+        let vars: HashMap<String, String> = [
+            ("$auth_type".to_string(), authenticator_type.to_string()),
+            ("$guid".to_string(), entity_guid.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
+        Ok(e.authenticator)
+    }
+
+    // Checks the code against each unused recovery code of the entity on the system and deletes the one it matches
+    pub fn consume_recovery_code(entity_guid: &str, system_guid: &str, code: &str) -> Result<bool, failure::Error> {
+        let code = recovery::normalize(code);
+        if code.len() != recovery::RECOVERY_CODE_LENGTH {
+            return Ok(false)
+        }
+        let stored = Self::find_by_entity_system(
+            &AuthenticatorType::recovery_code,
+            entity_guid,
+            system_guid,
+            vec!["uid".to_string(), "value".to_string()]
+        )?;
+        for a in stored {
+            let hash = match &a.value {
+                Some(hash) => hash,
+                None => continue
+            };
+            if argon2::verify_encoded(hash, code.as_bytes()).unwrap_or(false) {
+                let uid = a.uid.ok_or(AuthenticatorError::Empty())?;
+                db::delete(serde_json::to_vec(&json!({ "uid": uid }))?)?;
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    // Swaps the entity's unused recovery codes on the system for a new batch of argon2 hashes in a single
+    // transaction, so a failure part way leaves either the old codes or the new ones, never a mix
+    pub fn replace_recovery_codes(entity: &Entity, identifier: &Identifier, system: &System, hashes: Vec<String>) -> Result<usize, failure::Error> {
+        let entity_guid = entity.guid.as_ref().ok_or(AuthenticatorError::EmptyField("entity guid".to_string()))?;
+        let entity_uid = entity.uid.as_ref().ok_or(AuthenticatorError::EmptyField("entity uid".to_string()))?;
+        let identifier_uid = identifier.uid.as_ref().ok_or(AuthenticatorError::EmptyField("identifier uid".to_string()))?;
+        let system_guid = system.guid.as_ref().ok_or(AuthenticatorError::EmptyField("system guid".to_string()))?;
+        let system_uid = system.uid.as_ref().ok_or(AuthenticatorError::EmptyField("system uid".to_string()))?;
+
+        let stored = Self::find_by_entity_system(
+            &AuthenticatorType::recovery_code,
+            entity_guid,
+            system_guid,
+            vec!["uid".to_string(), "identifier { uid }".to_string()]
+        )?;
+        let mut delete = vec![];
+        for a in stored.iter() {
+            let uid = a.uid.as_ref().ok_or(AuthenticatorError::Empty())?;
+            delete.push(json!({ "uid": uid }));
+            delete.push(json!({ "uid": entity_uid, "authenticator": [{ "uid": uid }] }));
+            delete.push(json!({ "uid": system_uid, "authenticator": [{ "uid": uid }] }));
+            for i in a.identifiers.clone().unwrap_or_default() {
+                if let Some(old_identifier_uid) = i.uid {
+                    delete.push(json!({ "uid": old_identifier_uid, "authenticator": [{ "uid": uid }] }));
+                }
+            }
+        }
+
+        let codes: Vec<Authenticator> = hashes.into_iter().enumerate().map(|(n, hash)| {
+            Authenticator::new()
+                .uid(format!("_:recovery_code_{}", n))
+                .authenticator_type(AuthenticatorType::recovery_code)
+                .value(hash)
+                .add_entity(Entity { uid: Some(entity_uid.clone()), ..Default::default() })
+                .add_identifier(Identifier::new().uid(identifier_uid.clone()))
+                .add_system(System { uid: Some(system_uid.clone()), ..Default::default() })
+        }).collect();
+        let links: Vec<serde_json::Value> = codes.iter().map(|c| json!({ "uid": c.uid })).collect();
+        let set = json!([
+            { "uid": entity_uid, "authenticator": codes },
+            { "uid": identifier_uid, "authenticator": links },
+            { "uid": system_uid, "authenticator": links }
+        ]);
+
+        db::save_and_delete(serde_json::to_vec(&set)?, serde_json::to_vec(&delete)?)?;
+        Ok(stored.len())
+    }
+
+    // Second step after a successful password check for entities that enrolled a TOTP authenticator.
+    // Recovery codes are accepted in place of the current code, and failures of either count against
+    // the TOTP authenticator's lockout. A code is only good once: its time step must be later than the
+    // last one accepted.
+    pub fn verify_second_factor(entity_guid: &str, system_guid: &str, code: &str) -> Result<bool, failure::Error> {
+        let stored = Self::find_by_entity_system(
+            &AuthenticatorType::totp,
+            entity_guid,
+            system_guid,
+            vec![
+                "uid".to_string(),
+                "value".to_string(),
+                "failed_attempts".to_string(),
+                "last_failed_at".to_string(),
+                "locked_until".to_string(),
+                "last_used_step".to_string()
+            ]
+        )?;
+        let found = match stored.into_iter().next() {
+            Some(found) => found,
+            None => return Ok(false)
+        };
+        Self::_check_lockout(&found)?;
+        let sealed = found.value.clone().ok_or(AuthenticatorError::EmptyField("value".to_string()))?;
+        let secret = totp::decrypt_secret(&sealed)?;
+        let step = totp::matched_step(&secret, code, totp::now())?
+            .map(|step| step as i64)
+            .filter(|step| *step > found.last_used_step.unwrap_or(-1));
+        let verified = step.is_some() || Self::consume_recovery_code(entity_guid, system_guid, code)?;
+        Self::_record_attempt(&found, verified)?;
+        if let Some(step) = step {
+            Self::set_last_used_step(found.uid.as_ref().ok_or(AuthenticatorError::Empty())?, step)?;
+        }
+        Ok(verified)
+    }
+
+    pub fn set_last_used_step(uid: &str, step: i64) -> Result<(), failure::Error> {
+        let update = Authenticator {
+            uid: Some(uid.to_string()),
+            last_used_step: Some(step),
+            ..Default::default()
+        };
+        db::save(serde_json::to_vec(&update)?)?;
+        Ok(())
+    }
+
+    // An identifier can hold an authenticator of the type per System, so only the one on this System is
+    // kept. The failure counters are absent until the first failure, so they stay out of the @cascade.
+    fn _find_for_login(a: &Authenticator, i: &Identifier, s: &System) -> Result<Option<Authenticator>, failure::Error> {
         let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
             handlebars::Handlebars::new()
         });
@@ -296,8 +605,18 @@ impl AuthenticatorStore {
                     A as authenticator @filter(eq(authenticator_type, $auth_type))
                 }
 
-                authenticator(func: uid(A)) {
+                S as var(func: uid(A)) @cascade {
+                    system @filter(eq(guid, $sys_guid)) {
+                        uid
+                    }
+                }
+
+                authenticator(func: uid(S)) {
+                    uid
                     value
+                    failed_attempts
+                    last_failed_at
+                    locked_until
                     system @filter(eq(guid, $sys_guid)) {
                         uid
                     }
@@ -315,23 +634,229 @@ impl AuthenticatorStore {
         let res = db::query(query, vars)?;
         let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
         match e.authenticator.len() {
-            0 => return Ok(false),
+            0 => Ok(None),
             _ => {
                 let extracted_auth = e.authenticator.get(0).ok_or(AuthenticatorError::Empty())?.clone();
                 if extracted_auth.systems.is_none() {
-                    return Ok(false)
+                    return Ok(None)
                 }
-                let cmp = argon2::verify_encoded(extracted_auth.value.unwrap().as_str(), a.clone().value.unwrap().as_bytes());
-                match cmp {
-                    Ok(v) => {
-                        Ok(v)
-                    }
-                    Err(e) => {
-                        Err(e.into())
+                Ok(Some(extracted_auth))
+            }
+        }
+    }
+
+    pub fn login(a: Authenticator, i: Identifier, s: System) -> Result<bool, failure::Error> {
+        match a.authenticator_type.as_ref().ok_or(AuthenticatorError::Empty())? {
+            AuthenticatorType::email_password
+            | AuthenticatorType::phone_password
+            | AuthenticatorType::username_password => {},
+            // Key, link and second factor authenticators never hold an argon2 hash
+            _ => return Ok(false)
+        }
+        let presented = a.value.clone().ok_or(AuthenticatorError::EmptyField("value".to_string()))?;
+        // Unknown identifiers and throttled authenticators cost the same argon2 run as a wrong password,
+        // so response timing does not reveal which identifiers are registered
+        let extracted_auth = match Self::_find_for_login(&a, &i, &s)? {
+            Some(extracted_auth) => extracted_auth,
+            None => {
+                password::dummy_verify(presented.as_bytes());
+                return Ok(false)
+            }
+        };
+        if let Err(e) = Self::_check_lockout(&extracted_auth) {
+            password::dummy_verify(presented.as_bytes());
+            return Err(e)
+        }
+        let stored = extracted_auth.value.clone().ok_or(AuthenticatorError::Empty())?;
+        let cmp = argon2::verify_encoded(stored.as_str(), presented.as_bytes());
+        match cmp {
+            Ok(v) => {
+                Self::_record_attempt(&extracted_auth, v)?;
+                if v {
+                    Self::_upgrade_hash(&extracted_auth, &stored, &presented);
+                }
+                Ok(v)
+            }
+            Err(e) => {
+                Err(e.into())
+            }
+        }
+    }
+
+    // The plaintext is only available during a successful login, so that is when weaker hashes are replaced.
+    // A failed upgrade leaves the old hash in place and the login still succeeds.
+    fn _upgrade_hash(found: &Authenticator, stored: &str, presented: &str) {
+        let policy = password::policy();
+        if !policy.needs_rehash(stored) {
+            return
+        }
+        if let (Some(uid), Ok(rehashed)) = (found.uid.as_ref(), policy.hash(presented.as_bytes())) {
+            let _ = Self::set_value(uid, rehashed, vec!["uid".to_string()]);
+        }
+    }
+
+    // Refuses the attempt without checking the secret while the authenticator is backing off or locked
+    fn _check_lockout(found: &Authenticator) -> Result<(), failure::Error> {
+        match found.failure_state().check(lockout::now()) {
+            Verdict::Allowed => Ok(()),
+            Verdict::Backoff(seconds) => Err(AuthenticatorError::Throttled(seconds).into()),
+            Verdict::Locked(_) => Err(AuthenticatorError::Locked().into())
+        }
+    }
+
+    fn _record_attempt(found: &Authenticator, verified: bool) -> Result<(), failure::Error> {
+        let current = found.failure_state();
+        let next = if verified { FailureState::default() } else { current.record_failure(lockout::now(), lockout::MAX_FAILED_LOGINS) };
+        if next == current {
+            return Ok(())
+        }
+        let uid = found.uid.clone().ok_or(AuthenticatorError::Empty())?;
+        Self::set_failure_state(&uid, &next)
+    }
+
+    pub fn set_failure_state(uid: &str, state: &FailureState) -> Result<(), failure::Error> {
+        let update = Authenticator {
+            uid: Some(uid.to_string()),
+            ..Default::default()
+        }.with_failure_state(state);
+        db::save(serde_json::to_vec(&update)?)?;
+        Ok(())
+    }
+
+    // Admin unlock: clears failure counters on every authenticator of the entity
+    pub fn unlock_entity(entity_guid: &str) -> Result<usize, failure::Error> {
+        let entity = EntityStore::find_by_guid(entity_guid, vec!["uid".to_string(), "authenticator { uid }".to_string()])?
+            .ok_or(EntityError::DoesNotExist())?;
+        let authenticators = entity.authenicators.unwrap_or_default();
+        for a in authenticators.iter() {
+            Self::set_failure_state(a.uid.as_ref().ok_or(AuthenticatorError::Empty())?, &FailureState::default())?;
+        }
+        Ok(authenticators.len())
+    }
+
+    // a.value carries the base64 signature over message, checked against the registered public key
+    pub fn login_signature(a: Authenticator, i: Identifier, s: System, message: &[u8]) -> Result<bool, failure::Error> {
+        if a.authenticator_type != Some(AuthenticatorType::public_key_authentication) {
+            return Err(AuthenticatorError::AuthTypeIdentTypeMisMatch().into())
+        }
+        let signature = a.value.clone().ok_or(AuthenticatorError::EmptyField("value".to_string()))?;
+        // Unknown identifiers cost a signature check too, so timing does not reveal which are registered
+        let extracted_auth = match Self::_find_for_login(&a, &i, &s)? {
+            Some(extracted_auth) => extracted_auth,
+            None => {
+                public_key::dummy_verify(message, &signature);
+                return Ok(false)
+            }
+        };
+        if let Err(e) = Self::_check_lockout(&extracted_auth) {
+            public_key::dummy_verify(message, &signature);
+            return Err(e)
+        }
+        let public_key = extracted_auth.value.clone().ok_or(AuthenticatorError::Empty())?;
+        let verified = public_key::verify_signature(&public_key, message, &signature)?;
+        Self::_record_attempt(&extracted_auth, verified)?;
+        Ok(verified)
+    }
+
+    // a.value is the code texted when the login reached the code step; each code works once
+    pub fn login_phone_otp(a: Authenticator, i: Identifier, s: System) -> Result<bool, failure::Error> {
+        if a.authenticator_type != Some(AuthenticatorType::phone_otp) {
+            return Err(AuthenticatorError::AuthTypeIdentTypeMisMatch().into())
+        }
+        let code = a.value.clone().ok_or(AuthenticatorError::EmptyField("value".to_string()))?;
+        // Unknown identifiers run the same code lookup, so timing does not reveal which are registered
+        let extracted_auth = match Self::_find_for_login(&a, &i, &s)? {
+            Some(extracted_auth) => extracted_auth,
+            None => {
+                TokenStore::dummy_consume_code(&TokenPurpose::phone_otp, &code)?;
+                return Ok(false)
+            }
+        };
+        if let Err(e) = Self::_check_lockout(&extracted_auth) {
+            TokenStore::dummy_consume_code(&TokenPurpose::phone_otp, &code)?;
+            return Err(e)
+        }
+        let phone = i.value.clone().ok_or(AuthenticatorError::EmptyField("value".to_string()))?;
+        let identifier_uid = IdentifierStore::find_by_type_value(&IdentifierType::phone, &format!("^{}$", regex::escape(&phone)), vec!["uid".to_string()])?
+            .and_then(|i| i.uid)
+            .ok_or(AuthenticatorError::Empty())?;
+        let verified = TokenStore::consume_code(&TokenPurpose::phone_otp, &identifier_uid, &code)?;
+        Self::_record_attempt(&extracted_auth, verified)?;
+        if verified {
+            ThrottleStore::clear(&ThrottleStore::sms_key(&identifier_uid))?;
+        }
+        Ok(verified)
+    }
+
+    // Every passkey registered through the identifier for the system, decoded from Authenticator.value
+    pub fn find_webauthn_credentials(i: &Identifier, s: &System) -> Result<Vec<(String, StoredCredential)>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_AUTH_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query authenticator($auth_type: string, $ident_type: string, $ident_value: string, $sys_guid: string) {
+                identifier(func: eq(identifier_type, $ident_type)) @filter(eq(value, $ident_value)) {
+                    A as authenticator @filter(eq(authenticator_type, $auth_type))
+                }
+
+                authenticator(func: uid(A)) @cascade {
+                    uid
+                    value
+                    system @filter(eq(guid, $sys_guid)) {
+                        uid
                     }
                 }
+			}
+        "#;
+        let template_vars = &json!({});
+        let query = reg.render_template(req, template_vars)?;
+        let vars: HashMap<String, String> = [
+            ("$auth_type".to_string(), AuthenticatorType::webauthn.to_string()),
+            ("$ident_type".to_string(), format!("{}", i.identifier_type.as_ref().ok_or(AuthenticatorError::Empty())?)),
+            ("$ident_value".to_string(), format!("{}", i.value.as_ref().ok_or(AuthenticatorError::Empty())?)),
+            ("$sys_guid".to_string(), format!("{}", s.guid.as_ref().ok_or(AuthenticatorError::Empty())?))
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: AuthenticatorRoot = serde_json::from_slice(&res.json)?;
+        let mut credentials = vec![];
+        for a in e.authenticator {
+            if let (Some(uid), Some(value)) = (a.uid, a.value) {
+                credentials.push((uid, StoredCredential::from_value(&value)?));
             }
         }
+        Ok(credentials)
+    }
+
+    pub fn set_value(uid: &str, value: String, fields: Vec<String>) -> Result<Option<Authenticator>, failure::Error> {
+        let update = Authenticator {
+            uid: Some(uid.to_string()),
+            value: Some(value),
+            ..Default::default()
+        };
+        db::save(serde_json::to_vec(&update)?)?;
+
+        return Self::find_by_uid(uid, fields);
+    }
+
+    // Verifies a passkey assertion against the ceremony challenge and persists the advanced signature counter
+    pub fn login_webauthn(i: Identifier, s: System, challenge: &[u8], response: &AssertionResponse) -> Result<bool, failure::Error> {
+        let credentials = Self::find_webauthn_credentials(&i, &s)?;
+        let (uid, stored) = match credentials.into_iter().find(|(_, c)| c.credential_id == response.id.trim_end_matches('=')) {
+            Some(found) => found,
+            None => return Ok(false)
+        };
+        // Each passkey keeps its own failure counters, like password authenticators do
+        let found = Self::find_by_uid(&uid, vec![
+            "uid".to_string(),
+            "failed_attempts".to_string(),
+            "last_failed_at".to_string(),
+            "locked_until".to_string()
+        ])?.ok_or(AuthenticatorError::DoesNotExist())?;
+        Self::_check_lockout(&found)?;
+        let updated = webauthn::finish_assertion(challenge, &stored, response);
+        Self::_record_attempt(&found, updated.is_ok())?;
+        Self::set_value(&uid, updated?.to_value()?, vec!["uid".to_string()])?;
+        Ok(true)
     }
 
     pub fn find_by_uid(uid: &str, fields: Vec<String>) -> Result<Option<Authenticator>, failure::Error> {
@@ -361,4 +886,35 @@ impl AuthenticatorStore {
             _ => Ok(Some(e.authenticator.get(0).ok_or(AuthenticatorError::Empty())?.clone()))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shaped like the find_by_identifier_uid_type response, one authenticator per system guid
+    fn found(system_guids: &[&str]) -> Vec<Authenticator> {
+        let authenticators: Vec<serde_json::Value> = system_guids.iter().enumerate().map(|(n, guid)| json!({
+            "uid": format!("0x{}", n + 1),
+            "system": [{ "guid": guid }]
+        })).collect();
+        let root: AuthenticatorRoot = serde_json::from_value(json!({ "authenticator": authenticators })).unwrap();
+        root.authenticator
+    }
+
+    #[test]
+    fn totp_on_another_system_is_not_a_duplicate() {
+        assert!(AuthenticatorStore::_on_system(found(&["system-a"]), "system-b").is_none());
+    }
+
+    #[test]
+    fn totp_on_the_same_system_is_a_duplicate() {
+        let existing = AuthenticatorStore::_on_system(found(&["system-a", "system-b"]), "system-b").unwrap();
+        assert_eq!(existing.uid.as_deref(), Some("0x2"));
+    }
+
+    #[test]
+    fn authenticator_without_a_system_is_on_none() {
+        let root: AuthenticatorRoot = serde_json::from_value(json!({ "authenticator": [{ "uid": "0x1" }] })).unwrap();
+        assert!(AuthenticatorStore::_on_system(root.authenticator, "system-a").is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fmt::{Formatter, Display};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use failure_derive::*;
+use crate::entity::{Entity, EntityStore};
+use crate::identifier::IdentifierType;
+use crate::system::System;
+use crate::hydra::HydraConsentSession;
+use crate::scope::SCOPE_SEPARATOR;
+
+#[derive(Debug, Fail)]
+pub enum ClaimError {
+    #[fail(display = "Entity with guid {} does not exist", _0)]
+    UnknownSubject(String),
+    #[fail(display = "Claim mappings stored on the system are not valid: {}", _0)]
+    InvalidMappings(String)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClaimSource {
+    guid,
+    sid,
+    display_name,
+    email,
+    email_verified,
+    phone,
+    phone_verified,
+    scopes
+}
+
+impl ClaimSource {
+    // OIDC scope that must have been granted before the claim is released, None for claims every token carries
+    pub fn required_scope(&self) -> Option<&'static str> {
+        match self {
+            ClaimSource::email | ClaimSource::email_verified => Some("email"),
+            ClaimSource::phone | ClaimSource::phone_verified => Some("phone"),
+            ClaimSource::sid | ClaimSource::display_name => Some("profile"),
+            ClaimSource::guid | ClaimSource::scopes => None
+        }
+    }
+}
+
+impl Display for ClaimSource {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// Maps a claim name in each token onto the piece of the travs graph it is filled from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClaimMappings {
+    pub id_token: HashMap<String, ClaimSource>,
+    pub access_token: HashMap<String, ClaimSource>
+}
+
+impl Default for ClaimMappings {
+    fn default() -> Self {
+        let id_token: HashMap<String, ClaimSource> = [
+            ("guid".to_string(), ClaimSource::guid),
+            ("preferred_username".to_string(), ClaimSource::sid),
+            ("name".to_string(), ClaimSource::display_name),
+            ("email".to_string(), ClaimSource::email),
+            ("email_verified".to_string(), ClaimSource::email_verified),
+            ("phone_number".to_string(), ClaimSource::phone),
+            ("phone_number_verified".to_string(), ClaimSource::phone_verified)
+        ].iter().cloned().collect();
+        let access_token: HashMap<String, ClaimSource> = [
+            ("guid".to_string(), ClaimSource::guid),
+            ("scopes".to_string(), ClaimSource::scopes)
+        ].iter().cloned().collect();
+        ClaimMappings {
+            id_token,
+            access_token
+        }
+    }
+}
+
+impl ClaimMappings {
+    pub fn for_system(s: &System) -> Result<ClaimMappings, failure::Error> {
+        match &s.claim_mappings {
+            Some(raw) => serde_json::from_str(raw).map_err(|e| ClaimError::InvalidMappings(e.to_string()).into()),
+            None => Ok(ClaimMappings::default())
+        }
+    }
+}
+
+pub struct ClaimStore {}
+
+impl ClaimStore {
+    fn _entity_fields() -> Vec<String> {
+        vec![
+            "guid".to_string(),
+            "sid".to_string(),
+            "display_name".to_string(),
+            "identifier { identifier_type value verified }".to_string(),
+            "scope { name namespace { name system { guid } } }".to_string()
+        ]
+    }
+
+    fn _identifier_value(e: &Entity, identifier_type: IdentifierType) -> Option<Value> {
+        e.identifiers.as_ref()?
+            .iter()
+            .find(|i| i.identifier_type.as_ref() == Some(&identifier_type))
+            .and_then(|i| i.value.clone())
+            .map(Value::String)
+    }
+
+    // Mirrors _identifier_value, so the flag describes the same identifier the email or phone claim came from
+    fn _identifier_verified(e: &Entity, identifier_type: IdentifierType) -> Option<Value> {
+        e.identifiers.as_ref()?
+            .iter()
+            .find(|i| i.identifier_type.as_ref() == Some(&identifier_type))
+            .map(|i| Value::Bool(i.verified.unwrap_or(false)))
+    }
+
+    // Scopes are grouped by namespace name and limited to what was granted to the requesting system
+    fn _scopes(e: &Entity, s: &System, granted: &[String]) -> Value {
+        let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+        for scope in e.scopes.clone().unwrap_or_default() {
+            let name = match scope.name {
+                Some(name) => name,
+                None => continue
+            };
+            for ns in scope.namespaces.clone().unwrap_or_default() {
+                let belongs = ns.systems.clone().unwrap_or_default()
+                    .iter()
+                    .any(|ns_sys| ns_sys.guid.is_some() && ns_sys.guid == s.guid);
+                if !belongs {
+                    continue
+                }
+                if let Some(ns_name) = ns.name {
+                    if !granted.contains(&format!("{}{}{}", ns_name, SCOPE_SEPARATOR, name)) {
+                        continue
+                    }
+                    grouped.entry(ns_name).or_insert_with(Vec::new).push(name.clone());
+                }
+            }
+        }
+        json!(grouped)
+    }
+
+    // Identity claims are limited to the OIDC scopes granted, the same way _scopes limits namespace scopes
+    fn _resolve(source: &ClaimSource, e: &Entity, s: &System, granted: &[String]) -> Option<Value> {
+        if let Some(scope) = source.required_scope() {
+            if !granted.iter().any(|g| g == scope) {
+                return None
+            }
+        }
+        match source {
+            ClaimSource::guid => e.guid.clone().map(Value::String),
+            ClaimSource::sid => e.sid.clone().map(Value::String),
+            ClaimSource::display_name => e.display_name.clone().map(Value::String),
+            ClaimSource::email => Self::_identifier_value(e, IdentifierType::email),
+            ClaimSource::email_verified => Self::_identifier_verified(e, IdentifierType::email),
+            ClaimSource::phone => Self::_identifier_value(e, IdentifierType::phone),
+            ClaimSource::phone_verified => Self::_identifier_verified(e, IdentifierType::phone),
+            ClaimSource::scopes => Some(Self::_scopes(e, s, granted))
+        }
+    }
+
+    fn _claims(mappings: &HashMap<String, ClaimSource>, e: &Entity, s: &System, granted: &[String]) -> HashMap<String, Value> {
+        mappings.iter()
+            .filter_map(|(claim, source)| Self::_resolve(source, e, s, granted).map(|v| (claim.clone(), v)))
+            .collect()
+    }
+
+    pub fn session_for(subject: &str, s: &System, granted: &[String]) -> Result<HydraConsentSession, failure::Error> {
+        let mappings = ClaimMappings::for_system(s)?;
+        let e = EntityStore::find_by_guid(subject, Self::_entity_fields())?
+            .ok_or(ClaimError::UnknownSubject(subject.to_string()))?;
+        Ok(HydraConsentSession {
+            access_token: Self::_claims(&mappings.access_token, &e, s, granted),
+            id_token: Self::_claims(&mappings.id_token, &e, s, granted)
+        })
+    }
+}
@@ -0,0 +1,478 @@
+use std::time::Duration;
+use serde_derive::{Deserialize, Serialize};
+use failure_derive::*;
+use data_encoding::BASE64;
+use crate::hydra::{HydraClient, HydraCredential, DEFAULT_HYDRA_ADMIN_URL};
+use crate::password::{HashPolicy, HashVariant};
+use crate::mailer::{FileMailer, Mailer, SmtpMailer};
+use crate::sms::{HttpSmsGateway, SmsGateway};
+use std::sync::Arc;
+
+pub const CONFIG_ENV: &str = "TRAVS_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "./travs.toml";
+const ENV_PREFIX: &str = "TRAVS_";
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "Could not read {}: {}", _0, _1)]
+    Read(String, String),
+    #[fail(display = "Could not parse {}: {}", _0, _1)]
+    Parse(String, String),
+    #[fail(display = "Setting {} is invalid: {}", _0, _1)]
+    Invalid(String, String),
+    #[fail(display = "Setting {} must be set", _0)]
+    Missing(String)
+}
+
+// A secret is either written inline or read from a file, e.g. key = { file = "/run/secrets/csrf_key" }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    File { file: String }
+}
+
+impl Secret {
+    pub fn reveal(&self) -> Result<String, ConfigError> {
+        match self {
+            Secret::Value(v) => Ok(v.clone()),
+            Secret::File { file } => std::fs::read_to_string(file)
+                .map(|v| v.trim_end_matches(|c| c == '\n' || c == '\r').to_string())
+                .map_err(|e| ConfigError::Read(file.clone(), e.to_string()))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DgraphConfig {
+    pub address: String
+}
+
+impl Default for DgraphConfig {
+    fn default() -> Self {
+        DgraphConfig {
+            address: "localhost:9080".to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HydraConfig {
+    pub admin_url: String,
+    pub timeout_seconds: u64,
+    pub connect_timeout_seconds: u64,
+    pub bearer_token: Option<Secret>,
+    pub pkcs12_file: Option<String>,
+    pub pkcs12_password: Option<Secret>
+}
+
+impl Default for HydraConfig {
+    fn default() -> Self {
+        HydraConfig {
+            admin_url: DEFAULT_HYDRA_ADMIN_URL.to_string(),
+            timeout_seconds: 10,
+            connect_timeout_seconds: 5,
+            bearer_token: None,
+            pkcs12_file: None,
+            pkcs12_password: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CsrfConfig {
+    // Base64 encoding of the 32 byte AES-GCM key
+    pub key: Option<Secret>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub token: Option<Secret>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TotpConfig {
+    // Base64 encoding of the 32 byte AES-GCM key sealing stored TOTP secrets, TOTP is disabled without it
+    pub key: Option<Secret>,
+    // Shown next to the account name in authenticator apps
+    pub issuer: String
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        TotpConfig {
+            key: None,
+            issuer: "travs".to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebauthnConfig {
+    // Registrable domain passkeys are scoped to, it must match the host browsers see travs on
+    pub rp_id: String,
+    pub rp_name: String,
+    // Exact origin browsers report in clientDataJSON, e.g. https://login.example.com
+    pub origin: String
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        WebauthnConfig {
+            rp_id: "localhost".to_string(),
+            rp_name: "travs".to_string(),
+            origin: "http://localhost:8087".to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LockoutConfig {
+    // Throttle by the X-Forwarded-For / Forwarded client address instead of the socket peer. Behind a
+    // reverse proxy this must be on, or every client shares the proxy's address and one lockout holds
+    // up everyone; without a proxy that overwrites the header it must stay off, or clients pick their
+    // own address and dodge the per-address lockout
+    pub trust_forwarded_for: bool
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MailTransport {
+    none,
+    smtp,
+    file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MailConfig {
+    // Password reset, email confirmation and sign-in links are disabled while this is none
+    pub transport: MailTransport,
+    pub from: String,
+    pub smtp_host: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<Secret>,
+    // Directory the file transport writes one .eml file per message into
+    pub file_dir: String
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::none,
+            from: "travs@localhost".to_string(),
+            smtp_host: None,
+            smtp_username: None,
+            smtp_password: None,
+            file_dir: "./mail".to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SmsTransport {
+    none,
+    http
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SmsConfig {
+    // Phone verification and login codes cannot be sent while this is none
+    pub transport: SmsTransport,
+    // Endpoint the http transport posts {"to", "from", "body"} to
+    pub url: Option<String>,
+    pub from: String,
+    pub bearer_token: Option<Secret>
+}
+
+impl Default for SmsConfig {
+    fn default() -> Self {
+        SmsConfig {
+            transport: SmsTransport::none,
+            url: None,
+            from: "travs".to_string(),
+            bearer_token: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub bind_address: String,
+    // Where browsers reach travs, used to build links sent by mail
+    pub public_url: String,
+    pub template_dir: String,
+    pub dgraph: DgraphConfig,
+    pub hydra: HydraConfig,
+    pub csrf: CsrfConfig,
+    pub admin: AdminConfig,
+    pub totp: TotpConfig,
+    pub webauthn: WebauthnConfig,
+    pub lockout: LockoutConfig,
+    pub password: HashPolicy,
+    pub mail: MailConfig,
+    pub sms: SmsConfig
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "localhost:8087".to_string(),
+            public_url: "http://localhost:8087".to_string(),
+            template_dir: "./static".to_string(),
+            dgraph: DgraphConfig::default(),
+            hydra: HydraConfig::default(),
+            csrf: CsrfConfig::default(),
+            admin: AdminConfig::default(),
+            totp: TotpConfig::default(),
+            webauthn: WebauthnConfig::default(),
+            lockout: LockoutConfig::default(),
+            password: HashPolicy::default(),
+            mail: MailConfig::default(),
+            sms: SmsConfig::default()
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+// NAME sets the secret inline and NAME_FILE points it at a file
+fn env_secret(name: &str) -> Option<Secret> {
+    env(&format!("{}_FILE", name))
+        .map(|file| Secret::File { file })
+        .or_else(|| env(name).map(Secret::Value))
+}
+
+// Parsed straight into the field's type, so a value it cannot hold is an error rather than truncated
+fn env_number<T: std::str::FromStr>(name: &str, current: T) -> Result<T, ConfigError> {
+    match env(name) {
+        Some(v) => v.parse().map_err(|_| ConfigError::Invalid(
+            format!("{}{}", ENV_PREFIX, name),
+            format!("expected a whole number that fits in {}", std::any::type_name::<T>())
+        )),
+        None => Ok(current)
+    }
+}
+
+fn aes_key(name: &str, secret: &Secret) -> Result<[u8; 32], ConfigError> {
+    let decoded = BASE64.decode(secret.reveal()?.trim().as_bytes())
+        .map_err(|e| ConfigError::Invalid(name.to_string(), e.to_string()))?;
+    if decoded.len() != 32 {
+        return Err(ConfigError::Invalid(name.to_string(), "must decode to exactly 32 bytes".to_string()))
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+impl Config {
+    // Reads TRAVS_CONFIG (or ./travs.toml when present), then applies TRAVS_* environment overrides
+    pub fn load() -> Result<Config, ConfigError> {
+        let explicit = std::env::var(CONFIG_ENV).ok();
+        let path = explicit.clone().unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(raw) => toml::from_str(&raw).map_err(|e| ConfigError::Parse(path.clone(), e.to_string()))?,
+            Err(_) if explicit.is_none() => Config::default(),
+            Err(e) => return Err(ConfigError::Read(path, e.to_string()))
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(v) = env("BIND_ADDRESS") { self.bind_address = v }
+        if let Some(v) = env("PUBLIC_URL") { self.public_url = v }
+        if let Some(v) = env("TEMPLATE_DIR") { self.template_dir = v }
+        if let Some(v) = env("DGRAPH_ADDRESS") { self.dgraph.address = v }
+        if let Some(v) = env("HYDRA_ADMIN_URL") { self.hydra.admin_url = v }
+        if let Some(v) = env("HYDRA_PKCS12_FILE") { self.hydra.pkcs12_file = Some(v) }
+        if let Some(v) = env_secret("HYDRA_PKCS12_PASSWORD") { self.hydra.pkcs12_password = Some(v) }
+        if let Some(v) = env_secret("HYDRA_BEARER_TOKEN") { self.hydra.bearer_token = Some(v) }
+        if let Some(v) = env_secret("CSRF_KEY") { self.csrf.key = Some(v) }
+        if let Some(v) = env_secret("ADMIN_TOKEN") { self.admin.token = Some(v) }
+        if let Some(v) = env_secret("TOTP_KEY") { self.totp.key = Some(v) }
+        if let Some(v) = env("TOTP_ISSUER") { self.totp.issuer = v }
+        if let Some(v) = env("WEBAUTHN_RP_ID") { self.webauthn.rp_id = v }
+        if let Some(v) = env("WEBAUTHN_RP_NAME") { self.webauthn.rp_name = v }
+        if let Some(v) = env("WEBAUTHN_ORIGIN") { self.webauthn.origin = v }
+        if let Some(v) = env("MAIL_TRANSPORT") {
+            self.mail.transport = match v.as_str() {
+                "none" => MailTransport::none,
+                "smtp" => MailTransport::smtp,
+                "file" => MailTransport::file,
+                _ => return Err(ConfigError::Invalid(format!("{}MAIL_TRANSPORT", ENV_PREFIX), "expected none, smtp or file".to_string()))
+            }
+        }
+        if let Some(v) = env("MAIL_FROM") { self.mail.from = v }
+        if let Some(v) = env("MAIL_SMTP_HOST") { self.mail.smtp_host = Some(v) }
+        if let Some(v) = env("MAIL_SMTP_USERNAME") { self.mail.smtp_username = Some(v) }
+        if let Some(v) = env_secret("MAIL_SMTP_PASSWORD") { self.mail.smtp_password = Some(v) }
+        if let Some(v) = env("MAIL_FILE_DIR") { self.mail.file_dir = v }
+        if let Some(v) = env("SMS_TRANSPORT") {
+            self.sms.transport = match v.as_str() {
+                "none" => SmsTransport::none,
+                "http" => SmsTransport::http,
+                _ => return Err(ConfigError::Invalid(format!("{}SMS_TRANSPORT", ENV_PREFIX), "expected none or http".to_string()))
+            }
+        }
+        if let Some(v) = env("SMS_URL") { self.sms.url = Some(v) }
+        if let Some(v) = env("SMS_FROM") { self.sms.from = v }
+        if let Some(v) = env_secret("SMS_BEARER_TOKEN") { self.sms.bearer_token = Some(v) }
+        if let Some(v) = env("LOCKOUT_TRUST_FORWARDED_FOR") { self.lockout.trust_forwarded_for = v == "true" || v == "1" }
+        if let Some(v) = env("PASSWORD_VARIANT") {
+            self.password.variant = match v.as_str() {
+                "argon2i" => HashVariant::argon2i,
+                "argon2d" => HashVariant::argon2d,
+                "argon2id" => HashVariant::argon2id,
+                _ => return Err(ConfigError::Invalid(format!("{}PASSWORD_VARIANT", ENV_PREFIX), "expected argon2i, argon2d or argon2id".to_string()))
+            }
+        }
+        self.password.memory_kib = env_number("PASSWORD_MEMORY_KIB", self.password.memory_kib)?;
+        self.password.iterations = env_number("PASSWORD_ITERATIONS", self.password.iterations)?;
+        self.password.lanes = env_number("PASSWORD_LANES", self.password.lanes)?;
+        self.password.salt_length = env_number("PASSWORD_SALT_LENGTH", self.password.salt_length)?;
+        self.password.hash_length = env_number("PASSWORD_HASH_LENGTH", self.password.hash_length)?;
+        self.hydra.timeout_seconds = env_number("HYDRA_TIMEOUT_SECONDS", self.hydra.timeout_seconds)?;
+        self.hydra.connect_timeout_seconds = env_number("HYDRA_CONNECT_TIMEOUT_SECONDS", self.hydra.connect_timeout_seconds)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind_address.is_empty() {
+            return Err(ConfigError::Missing("bind_address".to_string()))
+        }
+        if self.dgraph.address.is_empty() {
+            return Err(ConfigError::Missing("dgraph.address".to_string()))
+        }
+        if !std::path::Path::new(&self.template_dir).is_dir() {
+            return Err(ConfigError::Invalid("template_dir".to_string(), format!("{} is not a directory", self.template_dir)))
+        }
+        if !self.hydra.admin_url.starts_with("http://") && !self.hydra.admin_url.starts_with("https://") {
+            return Err(ConfigError::Invalid("hydra.admin_url".to_string(), "expected an http or https url".to_string()))
+        }
+        if self.hydra.timeout_seconds == 0 || self.hydra.connect_timeout_seconds == 0 {
+            return Err(ConfigError::Invalid("hydra.timeout_seconds".to_string(), "timeouts must be greater than zero".to_string()))
+        }
+        if self.hydra.bearer_token.is_some() && self.hydra.pkcs12_file.is_some() {
+            return Err(ConfigError::Invalid("hydra".to_string(), "use either bearer_token or pkcs12_file, not both".to_string()))
+        }
+        self.csrf_key()?;
+        self.totp_key()?;
+        if !self.public_url.starts_with("http://") && !self.public_url.starts_with("https://") {
+            return Err(ConfigError::Invalid("public_url".to_string(), "expected an http or https url".to_string()))
+        }
+        if self.mail.transport == MailTransport::smtp && self.mail.smtp_host.is_none() {
+            return Err(ConfigError::Missing("mail.smtp_host".to_string()))
+        }
+        if self.mail.transport == MailTransport::file && !std::path::Path::new(&self.mail.file_dir).is_dir() {
+            return Err(ConfigError::Invalid("mail.file_dir".to_string(), format!("{} is not a directory", self.mail.file_dir)))
+        }
+        if self.sms.transport == SmsTransport::http {
+            match &self.sms.url {
+                Some(url) if url.starts_with("http://") || url.starts_with("https://") => {},
+                Some(_) => return Err(ConfigError::Invalid("sms.url".to_string(), "expected an http or https url".to_string())),
+                None => return Err(ConfigError::Missing("sms.url".to_string()))
+            }
+        }
+        if self.password.lanes == 0 || self.password.iterations == 0 {
+            return Err(ConfigError::Invalid("password".to_string(), "lanes and iterations must be greater than zero".to_string()))
+        }
+        if self.password.memory_kib < 8 * self.password.lanes {
+            return Err(ConfigError::Invalid("password.memory_kib".to_string(), "must be at least 8 KiB per lane".to_string()))
+        }
+        if self.password.salt_length < 8 || self.password.hash_length < 16 {
+            return Err(ConfigError::Invalid("password".to_string(), "salt_length must be at least 8 and hash_length at least 16".to_string()))
+        }
+        if self.webauthn.rp_id.is_empty() {
+            return Err(ConfigError::Missing("webauthn.rp_id".to_string()))
+        }
+        if !self.webauthn.origin.starts_with("http://") && !self.webauthn.origin.starts_with("https://") {
+            return Err(ConfigError::Invalid("webauthn.origin".to_string(), "expected an http or https origin".to_string()))
+        }
+        if let Some(token) = self.admin_token()? {
+            if token.len() < 32 {
+                return Err(ConfigError::Invalid("admin.token".to_string(), "must be at least 32 characters".to_string()))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn csrf_key(&self) -> Result<[u8; 32], ConfigError> {
+        let raw = self.csrf.key.as_ref().ok_or(ConfigError::Missing("csrf.key".to_string()))?;
+        aes_key("csrf.key", raw)
+    }
+
+    pub fn totp_key(&self) -> Result<Option<[u8; 32]>, ConfigError> {
+        match &self.totp.key {
+            Some(raw) => Ok(Some(aes_key("totp.key", raw)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn admin_token(&self) -> Result<Option<String>, ConfigError> {
+        match &self.admin.token {
+            Some(token) => Ok(Some(token.reveal()?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn mailer(&self) -> Result<Option<Arc<dyn Mailer>>, ConfigError> {
+        Ok(match self.mail.transport {
+            MailTransport::none => None,
+            MailTransport::smtp => {
+                let host = self.mail.smtp_host.clone().ok_or(ConfigError::Missing("mail.smtp_host".to_string()))?;
+                let mut smtp = SmtpMailer::new(self.mail.from.clone(), host);
+                if let (Some(username), Some(password)) = (&self.mail.smtp_username, &self.mail.smtp_password) {
+                    smtp = smtp.credentials(username.clone(), password.reveal()?);
+                }
+                Some(Arc::new(smtp))
+            },
+            MailTransport::file => Some(Arc::new(FileMailer::new(self.mail.file_dir.clone())))
+        })
+    }
+
+    pub fn sms_gateway(&self) -> Result<Option<Arc<dyn SmsGateway>>, ConfigError> {
+        Ok(match self.sms.transport {
+            SmsTransport::none => None,
+            SmsTransport::http => {
+                let url = self.sms.url.clone().ok_or(ConfigError::Missing("sms.url".to_string()))?;
+                let mut http = HttpSmsGateway::new(url, self.sms.from.clone())
+                    .map_err(|e| ConfigError::Invalid("sms.url".to_string(), e.to_string()))?;
+                if let Some(token) = &self.sms.bearer_token {
+                    http = http.bearer_token(token.reveal()?);
+                }
+                Some(Arc::new(http))
+            }
+        })
+    }
+
+    pub fn hydra_client(&self) -> Result<HydraClient, failure::Error> {
+        let mut builder = HydraClient::builder()
+            .base_url(self.hydra.admin_url.clone())
+            .timeout(Duration::from_secs(self.hydra.timeout_seconds))
+            .connect_timeout(Duration::from_secs(self.hydra.connect_timeout_seconds));
+        if let Some(token) = &self.hydra.bearer_token {
+            builder = builder.credential(HydraCredential::Bearer(token.reveal()?));
+        }
+        if let Some(file) = &self.hydra.pkcs12_file {
+            let der = std::fs::read(file).map_err(|e| ConfigError::Read(file.clone(), e.to_string()))?;
+            let password = match &self.hydra.pkcs12_password {
+                Some(password) => password.reveal()?,
+                None => String::new()
+            };
+            builder = builder.credential(HydraCredential::Pkcs12 { der, password });
+        }
+        Ok(builder.build()?)
+    }
+}
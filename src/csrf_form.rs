@@ -0,0 +1,94 @@
+use std::ops::Deref;
+use actix_web::{error, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::Payload;
+use actix_http::cookie::Cookie;
+use csrf::CsrfProtection;
+use data_encoding::BASE64;
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use crate::{session_cookie, AppData};
+
+pub const CSRF_COOKIE: &str = "_csrf";
+pub const CSRF_TTL_SECONDS: i64 = 3600;
+
+// Implemented by every form body that carries the _csrf token rendered into the page
+pub trait CsrfProtected {
+    fn csrf_token(&self) -> Option<String>;
+}
+
+impl CsrfProtected for Vec<(String, String)> {
+    fn csrf_token(&self) -> Option<String> {
+        self.iter().find(|(k, _)| k == "_csrf").map(|(_, v)| v.clone())
+    }
+}
+
+// A web::Form that only extracts once its _csrf field matches the _csrf cookie
+pub struct CsrfForm<T>(pub T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// Every rendered form gets a fresh token pair, so a verified token is never reused for the next page
+pub fn issue_token(data: &AppData<'_>) -> Result<(String, Cookie<'static>), Error> {
+    let generator = data.csrf_generator.lock().unwrap();
+    let (token, cookie) = generator.generate_token_pair(None, CSRF_TTL_SECONDS).map_err(error::ErrorInternalServerError)?;
+    drop(generator);
+
+    Ok((token.b64_string(), session_cookie(CSRF_COOKIE, cookie.b64_string())))
+}
+
+fn reject(data: &AppData<'_>) -> Error {
+    let body = data.hb.render("error", &json!({
+        "message": "Your session expired or the form was tampered with. Go back and try again."
+    })).unwrap_or_default();
+
+    error::InternalError::from_response("CSRF token verification failed", HttpResponse::Forbidden().content_type("text/html").body(body)).into()
+}
+
+fn verify(data: &AppData<'_>, token: Option<String>, cookie: Option<Cookie<'static>>) -> bool {
+    let (token, cookie) = match (token, cookie) {
+        (Some(token), Some(cookie)) => (token, cookie),
+        _ => return false
+    };
+    let (token, cookie) = match (BASE64.decode(token.as_bytes()), BASE64.decode(cookie.value().as_bytes())) {
+        (Ok(token), Ok(cookie)) => (token, cookie),
+        _ => return false
+    };
+    let generator = data.csrf_generator.lock().unwrap();
+    match (generator.parse_token(&token), generator.parse_cookie(&cookie)) {
+        (Ok(token), Ok(cookie)) => generator.verify_token_pair(&token, &cookie),
+        _ => false
+    }
+}
+
+impl<T> FromRequest for CsrfForm<T> where T: DeserializeOwned + CsrfProtected + 'static {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let form = web::Form::<T>::from_request(&req, payload);
+        Box::pin(async move {
+            let form = form.await?.into_inner();
+            let data = req.app_data::<web::Data<AppData<'static>>>()
+                .ok_or(error::ErrorInternalServerError("Application data is not configured"))?;
+            if !verify(data, form.csrf_token(), req.cookie(CSRF_COOKIE)) {
+                return Err(reject(data))
+            }
+            Ok(CsrfForm(form))
+        })
+    }
+}
@@ -3,10 +3,17 @@ use dgraph::make_dgraph;
 use std::collections::HashMap;
 
 static DB: OnceCell<dgraph::Dgraph> = OnceCell::new();
+static DB_ADDRESS: OnceCell<String> = OnceCell::new();
+
+// Must run before the first query, the connection is opened lazily with whatever address is set then
+pub fn configure(address: String) {
+    let _ = DB_ADDRESS.set(address);
+}
 
 pub fn get_connection() -> &'static dgraph::Dgraph {
     let db = DB.get_or_init(|| {
-        let dgraph = make_dgraph!(dgraph::new_dgraph_client("localhost:9080"));
+        let address = DB_ADDRESS.get().map(|a| a.as_str()).unwrap_or("localhost:9080");
+        let dgraph = make_dgraph!(dgraph::new_dgraph_client(address));
         dgraph
     });
     db
@@ -36,6 +43,20 @@ pub fn save(data: Vec<u8>) -> Result<dgraph::Response, dgraph::DgraphError> {
     mutate(mu)
 }
 
+pub fn delete(data: Vec<u8>) -> Result<dgraph::Response, dgraph::DgraphError> {
+    let mut mu= dgraph::Mutation::new();
+    mu.set_delete_json(data);
+    mutate(mu)
+}
+
+// Applies both halves in one transaction, so readers see either the old nodes or their replacements
+pub fn save_and_delete(set: Vec<u8>, delete: Vec<u8>) -> Result<dgraph::Response, dgraph::DgraphError> {
+    let mut mu= dgraph::Mutation::new();
+    mu.set_set_json(set);
+    mu.set_delete_json(delete);
+    mutate(mu)
+}
+
 pub fn drop_all() -> Result<dgraph::Payload, dgraph::DgraphError> {
     let db = get_connection();
     let op = dgraph::Operation {
@@ -292,6 +292,34 @@ impl EntityStore {
         }
     }
 
+    pub fn find_by_guid(guid: &str, fields: Vec<String>) -> Result<Option<Entity>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_ENTITY_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query entity($guid: string) {
+			    entity(func: eq(guid, $guid)) @filter(eq(dgraph.type, "Entity")) {
+			    {{#each fields }}
+				    {{this}}
+			    {{/each}}
+			}
+		}
+        "#;
+        let template_vars = &json!({
+            "fields": fields
+        });
+        let query = reg.render_template(req, template_vars)?;
+        let mut vars: HashMap<String, String> = [
+            ("$guid".to_string(), guid.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: EntityRoot = serde_json::from_slice(&res.json)?;
+        match e.entity.len() {
+            0 => Ok(None),
+            _ => Ok(Some(e.entity.get(0).ok_or(EntityError::Empty())?.clone()))
+        }
+    }
+
     pub fn find_by_sid(sid: &str, fields: Vec<String>) -> Result<Option<Entity>, failure::Error> {
         let reg = TEMPLATE_ENGINE_ENTITY_STORE.get_or_init(|| {
             handlebars::Handlebars::new()
@@ -1,10 +1,60 @@
 use std::collections::HashMap;
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize as SerializeBody;
 use serde_derive::{Serialize, Deserialize};
-use actix_http::Response;
+use failure_derive::*;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
 
-pub struct Hydra {}
+pub const DEFAULT_HYDRA_ADMIN_URL: &str = "http://localhost:4445";
 
-const hydraUrl: &str = "http://localhost:4445";
+#[derive(Debug, Fail)]
+pub enum HydraError {
+    #[fail(display = "Hydra rejected the request with status {}: {}", _0, _1)]
+    ClientError(u16, String),
+    #[fail(display = "Hydra failed to handle the request with status {}: {}", _0, _1)]
+    ServerError(u16, String),
+    #[fail(display = "Could not reach Hydra: {}", _0)]
+    Transport(#[cause] reqwest::Error),
+    #[fail(display = "Could not decode the Hydra response: {}", _0)]
+    Decode(#[cause] serde_json::Error),
+    #[fail(display = "Could not load the Hydra client credential: {}", _0)]
+    Credential(String)
+}
+
+impl From<reqwest::Error> for HydraError {
+    fn from(e: reqwest::Error) -> Self {
+        HydraError::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for HydraError {
+    fn from(e: serde_json::Error) -> Self {
+        HydraError::Decode(e)
+    }
+}
+
+impl ResponseError for HydraError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HydraError::ClientError(_, _) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::BAD_GATEWAY
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HydraErrorBody {
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    pub status_code: Option<u16>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HydraCompletedRequest {
+    pub redirect_to: String
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HydraAcceptLoginRequest {
@@ -13,97 +63,292 @@ pub struct HydraAcceptLoginRequest {
     pub remember_for: i32
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HydraConsentSession {
+    pub access_token: HashMap<String, serde_json::Value>,
+    pub id_token: HashMap<String, serde_json::Value>
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+pub struct HydraAcceptConsentRequest {
+    pub grant_scope: Vec<String>,
+    pub grant_access_token_audience: Vec<String>,
+    pub remember: bool,
+    pub remember_for: i32,
+    pub session: HydraConsentSession
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HydraRejectRequest {
+    pub error: String,
+    pub error_description: String,
+    pub status_code: i32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HydraConsentResponse {
+    pub challenge: String,
+    #[serde(default)]
+    pub requested_scope: Vec<String>,
+    pub requested_access_token_audience: Option<Vec<String>>,
+    #[serde(default)]
+    pub skip: bool,
+    #[serde(default)]
+    pub subject: String,
+    pub client: HydraOAuthClient,
+    #[serde(default)]
+    pub request_url: String,
+    pub login_challenge: Option<String>,
+    pub login_session_id: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HydraLogoutResponse {
+    #[serde(default)]
+    pub subject: String,
+    #[serde(default)]
+    pub sid: String,
+    #[serde(default)]
+    pub request_url: String,
+    #[serde(default)]
+    pub rp_initiated: bool
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HydraLoginResponse {
     pub challenge: String,
+    #[serde(default)]
     pub requested_scope: Vec<String>,
-    pub requested_access_token_audience: Option<String>,
+    pub requested_access_token_audience: Option<Vec<String>>,
+    #[serde(default)]
     pub skip: bool,
+    #[serde(default)]
     pub subject: String,
     // pub oidc_context: String,
-    pub client: HydraClient,
+    pub client: HydraOAuthClient,
+    #[serde(default)]
     pub request_url: String,
-    pub session_id: String
+    pub session_id: Option<String>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct HydraClient {
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HydraOAuthClient {
     pub client_id: String,
-    pub client_name: String,
-    pub redirect_uris: Vec<String>,
-    pub grant_types: Vec<String>,
-    pub response_types: Vec<String>,
-    pub scope: String,
-    pub audience: Option<String>,
-    pub owner: String,
-    pub policy_uri: String,
-    pub allowed_cors_origins: Option<String>,
-    pub tos_uri: String,
-    pub client_uri: String,
-    pub logo_uri: String,
-    pub contacts: Option<String>,
-    pub client_secret_expires_at: i32,
-    pub subject_type: String,
-    pub token_endpoint_auth_method: String,
-    pub userinfo_signed_response_alg: String,
-    pub created_at: String,
-    pub updated_at: String
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_uris: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grant_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_cors_origins: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tos_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contacts: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret_expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_endpoint_auth_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userinfo_signed_response_alg: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>
 }
 
-impl Hydra {
-    pub async fn get(flow: String, challenge: String) -> Result<String, failure::Error> {
-        let url = format!("{}/oauth2/auth/requests/{}?{}_challenge={}", hydraUrl, flow, flow, challenge);
-        let resp: reqwest::Response = reqwest::get(&url).await?;
+#[derive(Debug, Clone)]
+pub enum HydraCredential {
+    Bearer(String),
+    Pkcs12 { der: Vec<u8>, password: String }
+}
 
-        println!("DEBUG {:?}", resp);
+#[derive(Clone)]
+pub struct HydraClient {
+    base_url: String,
+    http: reqwest::Client
+}
 
-        return Ok(resp.text().await?);
+pub struct HydraClientBuilder {
+    base_url: String,
+    timeout: Duration,
+    connect_timeout: Duration,
+    credential: Option<HydraCredential>
+}
+
+impl HydraClientBuilder {
+    pub fn base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
     }
 
-    pub async fn put(flow: String, action: String, challenge: String, body: String) -> Result<String, failure::Error> {
-        let url = format!("{}/oauth2/auth/requests/{}/{}?{}_challenge={}", hydraUrl, flow, action, flow, challenge);
-        let client = reqwest::Client::new();
-        println!("DEBUG {:?}", body);
-        let resp: String = client.put(&url).body(body).send().await?.text().await?;
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
 
-        println!("DEBUG {:?}", resp.clone());
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
 
-        return Ok(resp.replace("\\u0026", "&"));
+    pub fn credential(mut self, credential: HydraCredential) -> Self {
+        self.credential = Some(credential);
+        self
     }
 
-    pub async fn get_login_request(challenge: String) -> Result<String, failure::Error> {
-        Self::get("login".to_string(), challenge).await
+    pub fn build(self) -> Result<HydraClient, HydraError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout);
+        match self.credential {
+            Some(HydraCredential::Bearer(token)) => {
+                let mut headers = reqwest::header::HeaderMap::new();
+                let value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|e| HydraError::Credential(e.to_string()))?;
+                headers.insert(reqwest::header::AUTHORIZATION, value);
+                builder = builder.default_headers(headers);
+            },
+            Some(HydraCredential::Pkcs12 { der, password }) => {
+                let identity = reqwest::Identity::from_pkcs12_der(&der, &password)?;
+                builder = builder.identity(identity);
+            },
+            None => {}
+        }
+        Ok(HydraClient {
+            base_url: self.base_url,
+            http: builder.build()?
+        })
+    }
+}
+
+impl HydraClient {
+    pub fn builder() -> HydraClientBuilder {
+        HydraClientBuilder {
+            base_url: DEFAULT_HYDRA_ADMIN_URL.to_string(),
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(5),
+            credential: None
+        }
+    }
+
+    async fn _handle_response(resp: reqwest::Response) -> Result<reqwest::Response, HydraError> {
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp)
+        }
+        let body = resp.text().await?;
+        let details: HydraErrorBody = serde_json::from_str(&body).unwrap_or_default();
+        let message = details.error_description.or(details.error).unwrap_or(body);
+        if status.is_client_error() {
+            return Err(HydraError::ClientError(status.as_u16(), message))
+        }
+        Err(HydraError::ServerError(status.as_u16(), message))
+    }
+
+    async fn _decode<T: DeserializeOwned>(resp: reqwest::Response) -> Result<T, HydraError> {
+        let resp = Self::_handle_response(resp).await?;
+        Ok(serde_json::from_slice(&resp.bytes().await?)?)
     }
 
-    pub async fn accept_login_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("login".to_string(), "accept".to_string(), challenge, body).await
+    async fn get<T: DeserializeOwned>(&self, flow: &str, challenge: &str) -> Result<T, HydraError> {
+        let url = format!("{}/oauth2/auth/requests/{}", self.base_url, flow);
+        let resp = self.http.get(&url)
+            .query(&[(format!("{}_challenge", flow), challenge)])
+            .send()
+            .await?;
+        Self::_decode(resp).await
     }
 
-    pub async fn reject_login_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("login".to_string(), "reject".to_string(), challenge, body).await
+    async fn put<B: SerializeBody>(&self, flow: &str, action: &str, challenge: &str, body: &B) -> Result<reqwest::Response, HydraError> {
+        let url = format!("{}/oauth2/auth/requests/{}/{}", self.base_url, flow, action);
+        let resp = self.http.put(&url)
+            .query(&[(format!("{}_challenge", flow), challenge)])
+            .json(body)
+            .send()
+            .await?;
+        Self::_handle_response(resp).await
     }
 
-    pub async fn get_consent_request(challenge: String) -> Result<String, failure::Error> {
-        Self::get("consent".to_string(), challenge).await
+    async fn complete<B: SerializeBody>(&self, flow: &str, action: &str, challenge: &str, body: &B) -> Result<HydraCompletedRequest, HydraError> {
+        let resp = self.put(flow, action, challenge, body).await?;
+        Ok(serde_json::from_slice(&resp.bytes().await?)?)
     }
 
-    pub async fn accept_consent_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("consent".to_string(), "accept".to_string(), challenge, body).await
+    pub async fn create_client(&self, client: &HydraOAuthClient) -> Result<HydraOAuthClient, HydraError> {
+        let url = format!("{}/clients", self.base_url);
+        Self::_decode(self.http.post(&url).json(client).send().await?).await
     }
 
-    pub async fn reject_consent_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("consent".to_string(), "reject".to_string(), challenge, body).await
+    pub async fn get_client(&self, client_id: &str) -> Result<HydraOAuthClient, HydraError> {
+        let url = format!("{}/clients/{}", self.base_url, client_id);
+        Self::_decode(self.http.get(&url).send().await?).await
     }
 
-    pub async fn get_logout_request(challenge: String) -> Result<String, failure::Error> {
-        Self::get("logout".to_string(), challenge).await
+    pub async fn update_client(&self, client_id: &str, client: &HydraOAuthClient) -> Result<HydraOAuthClient, HydraError> {
+        let url = format!("{}/clients/{}", self.base_url, client_id);
+        Self::_decode(self.http.put(&url).json(client).send().await?).await
     }
 
-    pub async fn accept_logout_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("logout".to_string(), "accept".to_string(), challenge, body).await
+    pub async fn delete_client(&self, client_id: &str) -> Result<(), HydraError> {
+        let url = format!("{}/clients/{}", self.base_url, client_id);
+        Self::_handle_response(self.http.delete(&url).send().await?).await?;
+        Ok(())
     }
 
-    pub async fn reject_logout_request(challenge: String, body: String) -> Result<String, failure::Error> {
-        Self::put("logout".to_string(), "reject".to_string(), challenge, body).await
+    pub async fn get_login_request(&self, challenge: &str) -> Result<HydraLoginResponse, HydraError> {
+        self.get("login", challenge).await
     }
-}
\ No newline at end of file
+
+    pub async fn accept_login_request(&self, challenge: &str, body: &HydraAcceptLoginRequest) -> Result<HydraCompletedRequest, HydraError> {
+        self.complete("login", "accept", challenge, body).await
+    }
+
+    pub async fn reject_login_request(&self, challenge: &str, body: &HydraRejectRequest) -> Result<HydraCompletedRequest, HydraError> {
+        self.complete("login", "reject", challenge, body).await
+    }
+
+    pub async fn get_consent_request(&self, challenge: &str) -> Result<HydraConsentResponse, HydraError> {
+        self.get("consent", challenge).await
+    }
+
+    pub async fn accept_consent_request(&self, challenge: &str, body: &HydraAcceptConsentRequest) -> Result<HydraCompletedRequest, HydraError> {
+        self.complete("consent", "accept", challenge, body).await
+    }
+
+    pub async fn reject_consent_request(&self, challenge: &str, body: &HydraRejectRequest) -> Result<HydraCompletedRequest, HydraError> {
+        self.complete("consent", "reject", challenge, body).await
+    }
+
+    pub async fn get_logout_request(&self, challenge: &str) -> Result<HydraLogoutResponse, HydraError> {
+        self.get("logout", challenge).await
+    }
+
+    pub async fn accept_logout_request(&self, challenge: &str) -> Result<HydraCompletedRequest, HydraError> {
+        self.complete("logout", "accept", challenge, &serde_json::json!({})).await
+    }
+
+    pub async fn reject_logout_request(&self, challenge: &str, body: &HydraRejectRequest) -> Result<(), HydraError> {
+        self.put("logout", "reject", challenge, body).await?;
+        Ok(())
+    }
+}
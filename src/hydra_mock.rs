@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde_json::json;
+use crate::hydra::{HydraLoginResponse, HydraConsentResponse, HydraLogoutResponse, HydraOAuthClient};
+
+// Every accept or reject call the mock receives, kept so tests can assert on what travs sent to Hydra
+#[derive(Debug, Clone)]
+pub struct MockCompletion {
+    pub flow: String,
+    pub action: String,
+    pub challenge: String,
+    pub body: serde_json::Value
+}
+
+#[derive(Default)]
+struct MockState {
+    login: HashMap<String, HydraLoginResponse>,
+    consent: HashMap<String, HydraConsentResponse>,
+    logout: HashMap<String, HydraLogoutResponse>,
+    completions: Vec<MockCompletion>
+}
+
+// In-process stand-in for the Hydra admin endpoints used by HydraClient, scripted with challenge fixtures
+#[derive(Clone, Default)]
+pub struct MockHydra {
+    state: Arc<Mutex<MockState>>
+}
+
+impl MockHydra {
+    pub fn new() -> MockHydra {
+        Default::default()
+    }
+
+    pub fn client(client_id: &str) -> HydraOAuthClient {
+        HydraOAuthClient {
+            client_id: client_id.to_string(),
+            client_name: Some(client_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn login_fixture(challenge: &str, client_id: &str, skip: bool, subject: &str) -> HydraLoginResponse {
+        HydraLoginResponse {
+            challenge: challenge.to_string(),
+            requested_scope: vec!["openid".to_string()],
+            requested_access_token_audience: None,
+            skip,
+            subject: subject.to_string(),
+            client: Self::client(client_id),
+            request_url: format!("http://hydra.mock/oauth2/auth?client_id={}", client_id),
+            session_id: None
+        }
+    }
+
+    pub fn consent_fixture(challenge: &str, client_id: &str, skip: bool, subject: &str, requested_scope: Vec<String>) -> HydraConsentResponse {
+        HydraConsentResponse {
+            challenge: challenge.to_string(),
+            requested_scope,
+            requested_access_token_audience: None,
+            skip,
+            subject: subject.to_string(),
+            client: Self::client(client_id),
+            request_url: format!("http://hydra.mock/oauth2/auth?client_id={}", client_id),
+            login_challenge: None,
+            login_session_id: None
+        }
+    }
+
+    pub fn logout_fixture(subject: &str, rp_initiated: bool) -> HydraLogoutResponse {
+        HydraLogoutResponse {
+            subject: subject.to_string(),
+            sid: nanoid::nanoid!(),
+            request_url: "http://hydra.mock/oauth2/sessions/logout".to_string(),
+            rp_initiated
+        }
+    }
+
+    pub fn add_login(&self, fixture: HydraLoginResponse) -> &Self {
+        self.state.lock().unwrap().login.insert(fixture.challenge.clone(), fixture);
+        self
+    }
+
+    pub fn add_consent(&self, fixture: HydraConsentResponse) -> &Self {
+        self.state.lock().unwrap().consent.insert(fixture.challenge.clone(), fixture);
+        self
+    }
+
+    pub fn add_logout(&self, challenge: &str, fixture: HydraLogoutResponse) -> &Self {
+        self.state.lock().unwrap().logout.insert(challenge.to_string(), fixture);
+        self
+    }
+
+    pub fn completions(&self, challenge: &str) -> Vec<MockCompletion> {
+        self.state.lock().unwrap().completions.iter()
+            .filter(|c| c.challenge == challenge)
+            .cloned()
+            .collect()
+    }
+
+    fn _challenge(flow: &str, query: &HashMap<String, String>) -> String {
+        query.get(&format!("{}_challenge", flow)).cloned().unwrap_or_default()
+    }
+
+    fn _not_found(challenge: &str) -> HttpResponse {
+        HttpResponse::NotFound().json(json!({
+            "error": "Not Found",
+            "error_description": format!("Unable to locate the requested resource for challenge {}", challenge),
+            "status_code": 404
+        }))
+    }
+
+    async fn get_request(path: web::Path<String>, query: web::Query<HashMap<String, String>>, mock: web::Data<MockHydra>) -> HttpResponse {
+        let flow = path.into_inner();
+        let challenge = Self::_challenge(&flow, &query);
+        let state = mock.state.lock().unwrap();
+        let fixture = match flow.as_str() {
+            "login" => state.login.get(&challenge).map(|f| json!(f)),
+            "consent" => state.consent.get(&challenge).map(|f| json!(f)),
+            "logout" => state.logout.get(&challenge).map(|f| json!(f)),
+            _ => None
+        };
+        match fixture {
+            Some(body) => HttpResponse::Ok().json(body),
+            None => Self::_not_found(&challenge)
+        }
+    }
+
+    async fn complete_request(path: web::Path<(String, String)>, query: web::Query<HashMap<String, String>>, body: web::Bytes, mock: web::Data<MockHydra>) -> HttpResponse {
+        let (flow, action) = path.into_inner();
+        let challenge = Self::_challenge(&flow, &query);
+        let mut state = mock.state.lock().unwrap();
+        let known = match flow.as_str() {
+            "login" => state.login.contains_key(&challenge),
+            "consent" => state.consent.contains_key(&challenge),
+            "logout" => state.logout.contains_key(&challenge),
+            _ => false
+        };
+        if !known {
+            return Self::_not_found(&challenge)
+        }
+        state.completions.push(MockCompletion {
+            flow: flow.clone(),
+            action: action.clone(),
+            challenge: challenge.clone(),
+            body: serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null)
+        });
+        if flow == "logout" && action == "reject" {
+            return HttpResponse::NoContent().finish()
+        }
+        HttpResponse::Ok().json(json!({
+            "redirect_to": format!("http://hydra.mock/{}/{}?challenge={}", flow, action, challenge)
+        }))
+    }
+
+    // Binds an ephemeral localhost port and returns the running server with the base url to give HydraClient
+    pub fn start(&self) -> std::io::Result<(actix_web::dev::Server, String)> {
+        let mock = web::Data::new(self.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .service(
+                    web::resource("/oauth2/auth/requests/{flow}")
+                        .route(web::get().to(MockHydra::get_request)))
+                .service(
+                    web::resource("/oauth2/auth/requests/{flow}/{action}")
+                        .route(web::put().to(MockHydra::complete_request)))
+        })
+            .workers(1)
+            .bind("127.0.0.1:0")?;
+        let addr = server.addrs().get(0).cloned()
+            .ok_or(std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "mock Hydra did not bind"))?;
+        Ok((server.run(), format!("http://{}", addr)))
+    }
+}
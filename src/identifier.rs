@@ -7,6 +7,7 @@ use serde_derive::{Deserialize, Serialize};
 use failure_derive::*;
 use crate::entity::{Entity, EntityStore, EntityError};
 use crate::authenticator::{Authenticator, AuthenticatorStore};
+use crate::lockout;
 
 #[derive(Debug, Fail)]
 pub enum IdentifierError {
@@ -39,6 +40,33 @@ pub enum IdentifierType {
     public_key
 }
 
+impl IdentifierType {
+    // Best guess at the identifier type a user typed into a single login field
+    pub fn infer(value: &str) -> IdentifierType {
+        let value = value.trim();
+        if value.starts_with("ssh-") || value.starts_with("ecdsa-") || value.starts_with("-----BEGIN") {
+            return IdentifierType::public_key
+        }
+        if value.contains('@') {
+            return IdentifierType::email
+        }
+        let digits = value.chars().filter(|c| c.is_ascii_digit()).count();
+        let phone_chars = value.chars().all(|c| c.is_ascii_digit() || "+-() .".contains(c));
+        if phone_chars && digits >= 7 {
+            return IdentifierType::phone
+        }
+        IdentifierType::username
+    }
+
+    // Only identifiers that can receive a code or link have a verification state
+    pub fn verifiable(&self) -> bool {
+        match self {
+            IdentifierType::email | IdentifierType::phone => true,
+            IdentifierType::username | IdentifierType::public_key => false
+        }
+    }
+}
+
 impl Display for IdentifierType {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -50,6 +78,9 @@ pub struct Identifier {
     pub uid: Option<String>,
     pub identifier_type: Option<IdentifierType>,
     pub value: Option<String>,
+    pub verified: Option<bool>,
+    pub verified_at: Option<i64>,
+    pub verification_sent_at: Option<i64>,
     #[serde(rename = "entity")]
     pub entities: Option<Vec<Entity>>,
     #[serde(rename = "authenticator")]
@@ -81,6 +112,21 @@ impl Identifier {
         self
     }
 
+    pub fn verified(mut self, verified: bool) -> Self {
+        self.verified = Some(verified);
+        self
+    }
+
+    pub fn verified_at(mut self, verified_at: i64) -> Self {
+        self.verified_at = Some(verified_at);
+        self
+    }
+
+    pub fn verification_sent_at(mut self, verification_sent_at: i64) -> Self {
+        self.verification_sent_at = Some(verification_sent_at);
+        self
+    }
+
     pub fn add_entity(mut self, entity: Entity) -> Self {
         if self.entities.is_none() {
             self.entities = Some(vec![])
@@ -221,6 +267,18 @@ impl IdentifierStore {
         }
     }
 
+    pub fn mark_verified(uid: &str) -> Result<(), failure::Error> {
+        let update = Identifier::new().uid(uid.to_string()).verified(true).verified_at(lockout::now());
+        db::save(serde_json::to_vec(&update)?)?;
+        Ok(())
+    }
+
+    pub fn mark_verification_sent(uid: &str) -> Result<(), failure::Error> {
+        let update = Identifier::new().uid(uid.to_string()).verification_sent_at(lockout::now());
+        db::save(serde_json::to_vec(&update)?)?;
+        Ok(())
+    }
+
     pub fn associate_authenticator(uid: &str, e: Authenticator, fields: Vec<String>) -> Result<Option<Identifier>, failure::Error> {
         let res = Self::find_by_uid(uid, vec!["uid".to_string(), "guid".to_string(), "authenticator { uid }".to_string()])?;
         if res.is_none() {
@@ -0,0 +1,227 @@
+use serde_json::json;
+use once_cell::sync::OnceCell;
+use crate::db;
+use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use failure_derive::*;
+
+// Consecutive failures before an identifier is locked out
+pub const MAX_FAILED_LOGINS: i32 = 5;
+// Failures before a client address is locked out. Far above MAX_FAILED_LOGINS because many users can
+// share one address behind NAT or a proxy, and it only holds up if the address is the real client's
+// (see LockoutConfig::trust_forwarded_for)
+pub const MAX_FAILED_LOGINS_PER_IP: i32 = 100;
+// Wait after the first failure, doubled for every further failure up to BACKOFF_MAX_SECONDS
+pub const BACKOFF_BASE_SECONDS: i64 = 1;
+pub const BACKOFF_MAX_SECONDS: i64 = 300;
+// How long a lockout lasts, and how long without failures before the counter starts over
+pub const LOCKOUT_SECONDS: i64 = 900;
+
+#[derive(Debug, Fail)]
+pub enum ThrottleError {
+    #[fail(display = "Cannot extract throttle value from an empty array or None value")]
+    Empty()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    // Seconds until the next attempt is accepted
+    Backoff(i64),
+    Locked(i64)
+}
+
+// Failure counters as persisted on Authenticator and Throttle nodes, so every travs instance sees them
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FailureState {
+    pub failed_attempts: i32,
+    pub last_failed_at: i64,
+    pub locked_until: i64
+}
+
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl FailureState {
+    pub fn new(failed_attempts: Option<i32>, last_failed_at: Option<i64>, locked_until: Option<i64>) -> FailureState {
+        FailureState {
+            failed_attempts: failed_attempts.unwrap_or(0),
+            last_failed_at: last_failed_at.unwrap_or(0),
+            locked_until: locked_until.unwrap_or(0)
+        }
+    }
+
+    fn backoff_seconds(&self) -> i64 {
+        if self.failed_attempts <= 0 {
+            return 0
+        }
+        let exponent = (self.failed_attempts - 1).min(30) as u32;
+        (BACKOFF_BASE_SECONDS << exponent).min(BACKOFF_MAX_SECONDS)
+    }
+
+    pub fn check(&self, now: i64) -> Verdict {
+        if self.locked_until > now {
+            return Verdict::Locked(self.locked_until - now)
+        }
+        let retry_at = self.last_failed_at + self.backoff_seconds();
+        if self.failed_attempts > 0 && retry_at > now {
+            return Verdict::Backoff(retry_at - now)
+        }
+        Verdict::Allowed
+    }
+
+    pub fn record_failure(&self, now: i64, max_failures: i32) -> FailureState {
+        // A quiet period as long as a lockout forgives earlier failures
+        let previous = if now - self.last_failed_at > LOCKOUT_SECONDS { 0 } else { self.failed_attempts };
+        let failed_attempts = previous + 1;
+        FailureState {
+            failed_attempts,
+            last_failed_at: now,
+            locked_until: if failed_attempts >= max_failures { now + LOCKOUT_SECONDS } else { 0 }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThrottleRoot {
+    pub throttle: Vec<Throttle>
+}
+
+// Failure counters for something that is not a node of its own, such as a client address
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Throttle {
+    pub uid: Option<String>,
+    pub throttle_key: Option<String>,
+    pub failed_attempts: Option<i32>,
+    pub last_failed_at: Option<i64>,
+    pub locked_until: Option<i64>,
+    #[serde(rename = "dgraph.type")]
+    pub dtype: Option<Vec<String>>
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle {
+            dtype: Some(vec!["Throttle".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    pub fn throttle_key(mut self, throttle_key: String) -> Self {
+        self.throttle_key = Some(throttle_key);
+        self
+    }
+
+    pub fn failure_state(&self) -> FailureState {
+        FailureState::new(self.failed_attempts, self.last_failed_at, self.locked_until)
+    }
+
+    pub fn with_failure_state(mut self, state: &FailureState) -> Self {
+        self.failed_attempts = Some(state.failed_attempts);
+        self.last_failed_at = Some(state.last_failed_at);
+        self.locked_until = Some(state.locked_until);
+        self
+    }
+}
+
+pub struct ThrottleStore {}
+
+static TEMPLATE_ENGINE_THROTTLE_STORE: OnceCell<handlebars::Handlebars> = OnceCell::new();
+
+impl ThrottleStore {
+    pub fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    // Codes texted to a phone identifier are counted like failures, so every send backs off the next
+    // and a burst of MAX_FAILED_LOGINS stops sending for LOCKOUT_SECONDS
+    pub fn sms_key(identifier_uid: &str) -> String {
+        format!("sms:{}", identifier_uid)
+    }
+
+    // Password reset mails are counted per address and per email the same way, so neither a mailbox
+    // nor the mailer can be flooded from one place
+    pub fn reset_ip_key(ip: &str) -> String {
+        format!("reset-ip:{}", ip)
+    }
+
+    pub fn reset_email_key(email: &str) -> String {
+        format!("reset-email:{}", email.to_lowercase())
+    }
+
+    // Sign-in link mails are counted the same way as password reset mails
+    pub fn link_ip_key(ip: &str) -> String {
+        format!("link-ip:{}", ip)
+    }
+
+    pub fn link_email_key(email: &str) -> String {
+        format!("link-email:{}", email.to_lowercase())
+    }
+
+    pub fn find_by_key(key: &str, fields: Vec<String>) -> Result<Option<Throttle>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_THROTTLE_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query throttle($key: string) {
+			    throttle(func: eq(throttle_key, $key)) @filter(eq(dgraph.type, "Throttle")) {
+			    {{#each fields }}
+				    {{this}}
+			    {{/each}}
+			}
+		}
+        "#;
+        let template_vars = &json!({
+            "fields": fields
+        });
+        let query = reg.render_template(req, template_vars)?;
+        let vars: HashMap<String, String> = [
+            ("$key".to_string(), key.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: ThrottleRoot = serde_json::from_slice(&res.json)?;
+        match e.throttle.len() {
+            0 => Ok(None),
+            _ => Ok(Some(e.throttle.get(0).ok_or(ThrottleError::Empty())?.clone()))
+        }
+    }
+
+    fn _state_fields() -> Vec<String> {
+        vec![
+            "uid".to_string(),
+            "throttle_key".to_string(),
+            "failed_attempts".to_string(),
+            "last_failed_at".to_string(),
+            "locked_until".to_string()
+        ]
+    }
+
+    pub fn check(key: &str) -> Result<Verdict, failure::Error> {
+        Ok(match Self::find_by_key(key, Self::_state_fields())? {
+            Some(t) => t.failure_state().check(now()),
+            None => Verdict::Allowed
+        })
+    }
+
+    pub fn record_failure(key: &str, max_failures: i32) -> Result<FailureState, failure::Error> {
+        let existing = Self::find_by_key(key, Self::_state_fields())?;
+        let throttle = existing.unwrap_or_else(|| Throttle::new().throttle_key(key.to_string()));
+        let state = throttle.failure_state().record_failure(now(), max_failures);
+        db::save(serde_json::to_vec(&throttle.with_failure_state(&state))?)?;
+        Ok(state)
+    }
+
+    pub fn clear(key: &str) -> Result<bool, failure::Error> {
+        match Self::find_by_key(key, Self::_state_fields())? {
+            Some(t) => {
+                db::save(serde_json::to_vec(&t.with_failure_state(&FailureState::default()))?)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+}
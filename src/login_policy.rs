@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::hydra::HydraRejectRequest;
+
+pub const MAX_LOGIN_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginRejection {
+    TooManyAttempts,
+    Cancelled,
+    Locked,
+    Throttled,
+    UnknownClient
+}
+
+impl LoginRejection {
+    pub fn to_reject_request(&self) -> HydraRejectRequest {
+        let (error, description, status_code) = match self {
+            LoginRejection::TooManyAttempts => ("access_denied", "The user failed to authenticate too many times", 401),
+            LoginRejection::Cancelled => ("access_denied", "The user cancelled the login", 403),
+            // Worded like any other failure, since saying the account is locked says it exists
+            LoginRejection::Locked => ("access_denied", "The user failed to authenticate too many times", 401),
+            LoginRejection::Throttled => ("temporarily_unavailable", "Too many failed logins from this address, try again later", 429),
+            LoginRejection::UnknownClient => ("unauthorized_client", "The OAuth client is not bound to a travs system", 400)
+        };
+        HydraRejectRequest {
+            error: error.to_string(),
+            error_description: description.to_string(),
+            status_code
+        }
+    }
+}
+
+// Failed attempts are counted per Hydra login challenge, so a fresh authorization request starts at zero
+pub struct LoginAttempts {
+    max_attempts: u32,
+    failures: Mutex<HashMap<String, u32>>
+}
+
+impl LoginAttempts {
+    pub fn new(max_attempts: u32) -> LoginAttempts {
+        LoginAttempts {
+            max_attempts,
+            failures: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn record_failure(&self, challenge: &str) -> Option<LoginRejection> {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(challenge.to_string()).or_insert(0);
+        *count += 1;
+        if *count >= self.max_attempts {
+            failures.remove(challenge);
+            return Some(LoginRejection::TooManyAttempts)
+        }
+        None
+    }
+
+    pub fn clear(&self, challenge: &str) {
+        self.failures.lock().unwrap().remove(challenge);
+    }
+}
@@ -0,0 +1,216 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::form_urlencoded;
+use crate::AppData;
+use crate::authenticator::{AuthenticatorStore, AuthenticatorType};
+use crate::csrf_form::{CsrfForm, CsrfProtected, issue_token};
+use crate::identifier::{Identifier, IdentifierStore, IdentifierType};
+use crate::lockout::{ThrottleStore, Verdict, MAX_FAILED_LOGINS};
+use crate::login_policy::LoginRejection;
+use crate::mailer::{MailMessage, Mailer};
+use crate::system::System;
+use crate::token::{TokenPurpose, TokenStore};
+
+pub const LINK_TTL_SECONDS: i64 = 600;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkReq {
+    identifier: String,
+    challenge: String,
+    remember: Option<String>,
+    _csrf: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for LinkReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkQuery {
+    challenge: String,
+    token: String,
+    remember: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfirmReq {
+    challenge: String,
+    token: String,
+    remember: Option<String>,
+    _csrf: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for ConfirmReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+// Links only work for identifiers with an email_link authenticator on the System of the login
+fn enabled(identifier_uid: &str, system: &System) -> Result<bool, failure::Error> {
+    let authenticators = AuthenticatorStore::find_by_identifier_uid_type(identifier_uid, &AuthenticatorType::email_link, vec!["system { guid }".to_string()])?;
+    Ok(authenticators.iter().any(|a| {
+        a.systems.as_ref()
+            .map(|systems| systems.iter().any(|s| s.guid.is_some() && s.guid == system.guid))
+            .unwrap_or(false)
+    }))
+}
+
+fn render_error(data: &AppData<'_>, message: &str) -> Result<HttpResponse, Error> {
+    let body = data.hb.render("error", &json!({ "message": message })).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::BadRequest().content_type("text/html").body(body))
+}
+
+// Looks the email up, issues a token bound to the challenge and mails the link. Runs after the response has gone out.
+fn send_login_link(mailer: &dyn Mailer, public_url: &str, system: &System, email: &str, challenge: &str, remember: bool) -> Result<(), failure::Error> {
+    let identifier = IdentifierStore::find_by_type_value(&IdentifierType::email, &format!("^{}$", regex::escape(email)), vec!["uid".to_string()])?;
+    let identifier_uid = match identifier.and_then(|i| i.uid) {
+        Some(identifier_uid) => identifier_uid,
+        None => return Ok(())
+    };
+    if !enabled(&identifier_uid, system)? {
+        return Ok(())
+    }
+
+    let token = TokenStore::issue_bound(TokenPurpose::login_link, &identifier_uid, LINK_TTL_SECONDS, challenge)?;
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair("challenge", challenge).append_pair("token", &token);
+    if remember {
+        query.append_pair("remember", "1");
+    }
+    let link = format!("{}/login/link?{}", public_url, query.finish());
+    mailer.send(&MailMessage {
+        to: email.to_string(),
+        subject: "Your sign-in link".to_string(),
+        body: format!("Follow this link within 10 minutes, in the browser you started from, to log in as {}:\n{}\n\nIf you did not try to log in, ignore this message.", email, link)
+    })?;
+    Ok(())
+}
+
+// The response does not depend on whether the email is registered: the lookup and the mail happen in
+// the background, and throttled or failed sends show the same page, so it cannot be used to probe for accounts
+pub async fn send_link(item: CsrfForm<LinkReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return crate::reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    let mailer = data.mailer.clone().ok_or(error::ErrorNotFound("Sign-in links are not enabled"))?;
+
+    let login_request = data.hydra.get_login_request(&challenge).await?;
+    let system = match crate::system_for_client(&login_request.client.client_id)? {
+        Some(system) => system,
+        None => return crate::reject_login(&data, &challenge, LoginRejection::UnknownClient).await
+    };
+
+    // Every link sent counts as an attempt, so one challenge cannot be used to flood a mailbox
+    if let Some(rejection) = data.login_attempts.record_failure(&challenge) {
+        return crate::reject_login(&data, &challenge, rejection).await
+    }
+
+    // Challenges are free to mint, so sends are also throttled per email and per address
+    let email = item.identifier.trim().to_string();
+    let mut keys = vec![ThrottleStore::link_email_key(&email)];
+    let ip = crate::client_ip(&req, data.trust_forwarded_for);
+    if !ip.is_empty() {
+        keys.push(ThrottleStore::link_ip_key(&ip));
+    }
+    let mut allowed = true;
+    for key in keys.iter() {
+        allowed &= ThrottleStore::check(key).map_err(error::ErrorInternalServerError)? == Verdict::Allowed;
+    }
+    // Every request counts, sent or not, so the backoff does not reveal which emails get mail either
+    for key in keys.iter() {
+        ThrottleStore::record_failure(key, MAX_FAILED_LOGINS).map_err(error::ErrorInternalServerError)?;
+    }
+
+    if allowed {
+        let public_url = data.public_url.clone();
+        let to = email.clone();
+        let challenge = challenge.clone();
+        let remember = item.remember.is_some();
+        actix_rt::spawn(async move {
+            let sent = web::block(move || send_login_link(mailer.as_ref(), &public_url, &system, &to, &challenge, remember)).await;
+            if let Err(e) = sent {
+                eprintln!("Could not send a sign-in link: {}", e);
+            }
+        });
+    }
+
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge.clone(),
+        "csrf_token": token,
+        "identifier": email,
+        "sent": true
+    });
+
+    let body = data.hb.render("login_link", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+// Target of the mailed link. Mail scanners fetch links to check them, so opening it only asks for
+// confirmation; the token is left for the POST from that page.
+pub async fn follow_link(query: web::Query<LinkQuery>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let tmpl_data = json!({
+        "challenge": query.challenge.clone(),
+        "token": query.token.clone(),
+        "remember": query.remember.is_some(),
+        "csrf_token": token
+    });
+
+    let body = data.hb.render("login_link_confirm", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string())
+        .header(actix_web::http::header::CACHE_CONTROL, "no-store")
+        .body(body))
+}
+
+// The token only matches the challenge it was sent for and is used up here, on the first confirmation
+pub async fn confirm_link(item: CsrfForm<ConfirmReq>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return crate::reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    let login_request = data.hydra.get_login_request(&challenge).await?;
+    let system = match crate::system_for_client(&login_request.client.client_id)? {
+        Some(system) => system,
+        None => return crate::reject_login(&data, &challenge, LoginRejection::UnknownClient).await
+    };
+
+    let identifier_uid = match TokenStore::consume_bound(&TokenPurpose::login_link, &challenge, &item.token).map_err(error::ErrorInternalServerError)? {
+        Some(identifier_uid) => identifier_uid,
+        None => return render_error(&data, "This sign-in link is invalid or has expired. Go back and ask for a new one.")
+    };
+    if !enabled(&identifier_uid, &system).map_err(error::ErrorInternalServerError)? {
+        return render_error(&data, "This sign-in link is invalid or has expired. Go back and ask for a new one.")
+    }
+
+    let email = IdentifierStore::find_by_uid(&identifier_uid, vec!["value".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .and_then(|i| i.value)
+        .ok_or(error::ErrorInternalServerError("Identifier does not exist"))?;
+
+    // Opening the link proves control of the mailbox
+    IdentifierStore::mark_verified(&identifier_uid).map_err(error::ErrorInternalServerError)?;
+
+    data.login_attempts.clear(&challenge);
+
+    let identifier = Identifier::new().identifier_type(IdentifierType::email).value(email);
+
+    crate::first_factor_passed(&data, &challenge, &system, &identifier, item.remember.is_some(), false, false).await
+}
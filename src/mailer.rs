@@ -0,0 +1,113 @@
+#[cfg(test)]
+use std::sync::Mutex;
+use failure_derive::*;
+use lettre::smtp::authentication::Credentials;
+use lettre::{SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+
+#[derive(Debug, Fail)]
+pub enum MailerError {
+    #[fail(display = "Could not build mail: {}", _0)]
+    Build(String),
+    #[fail(display = "Could not deliver mail: {}", _0)]
+    Delivery(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+// Outgoing mail for account recovery and verification links
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &MailMessage) -> Result<(), MailerError>;
+}
+
+// Delivers over implicit TLS on the submissions port (465) of host
+pub struct SmtpMailer {
+    from: String,
+    host: String,
+    credentials: Option<(String, String)>
+}
+
+impl SmtpMailer {
+    pub fn new(from: String, host: String) -> SmtpMailer {
+        SmtpMailer {
+            from,
+            host,
+            credentials: None
+        }
+    }
+
+    pub fn credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailerError> {
+        let email = EmailBuilder::new()
+            .to(message.to.as_str())
+            .from(self.from.as_str())
+            .subject(message.subject.as_str())
+            .text(message.body.as_str())
+            .build()
+            .map_err(|e| MailerError::Build(e.to_string()))?;
+        let mut client = SmtpClient::new_simple(&self.host)
+            .map_err(|e| MailerError::Delivery(e.to_string()))?;
+        if let Some((username, password)) = &self.credentials {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        client.transport().send(email.into())
+            .map(|_| ())
+            .map_err(|e| MailerError::Delivery(e.to_string()))
+    }
+}
+
+// Writes each message to its own file, for development without an SMTP server
+pub struct FileMailer {
+    dir: String
+}
+
+impl FileMailer {
+    pub fn new(dir: String) -> FileMailer {
+        FileMailer { dir }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailerError> {
+        let path = std::path::Path::new(&self.dir).join(format!("{}.eml", nanoid::nanoid!()));
+        let contents = format!("To: {}\r\nSubject: {}\r\n\r\n{}\r\n", message.to, message.subject, message.body);
+        std::fs::write(&path, contents).map_err(|e| MailerError::Delivery(e.to_string()))
+    }
+}
+
+// Keeps every message so tests can read the links travs sent; never selectable in a deployed config
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<MailMessage>>
+}
+
+#[cfg(test)]
+impl MemoryMailer {
+    pub fn new() -> MemoryMailer {
+        Default::default()
+    }
+
+    pub fn sent(&self) -> Vec<MailMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Mailer for MemoryMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailerError> {
+        self.sent.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
@@ -13,22 +13,30 @@ extern crate csrf;
 extern crate data_encoding;
 
 
-use crate::identifier::{IdentifierError, IdentifierType, Identifier};
-use crate::entity::EntityError;
-use crate::system::{SystemError, System};
-use crate::authenticator::{AuthenticatorError, Authenticator};
+use crate::identifier::{IdentifierError, IdentifierType, Identifier, IdentifierStore};
+use crate::entity::{EntityError, EntityStore};
+use crate::claims::ClaimStore;
+use crate::system::{SystemError, System, SystemStore};
+use crate::authenticator::{AuthenticatorError, Authenticator, AuthenticatorStore, AuthenticatorType};
 use crate::namespace::NamespaceError;
-use crate::scope::ScopeError;
+use crate::scope::{ScopeError, ScopeStore};
 use actix_web::{
-    error, middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer,
+    error, middleware, web, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
 };
+use actix_web::error::BlockingError;
 use bytes::{Bytes, BytesMut};
 use futures::StreamExt;
 use json::JsonValue;
 use serde::{Deserialize, Serialize};
 use rand::rngs::OsRng;
 use rand::RngCore;
-use crate::hydra::{Hydra, HydraLoginResponse, HydraAcceptLoginRequest};
+use crate::hydra::{HydraClient, HydraCompletedRequest, HydraAcceptLoginRequest, HydraAcceptConsentRequest, HydraConsentResponse, HydraConsentSession, HydraRejectRequest};
+use crate::csrf_form::{CsrfForm, CsrfProtected, issue_token};
+use crate::login_policy::{LoginAttempts, LoginRejection, MAX_LOGIN_ATTEMPTS};
+use crate::mfa::{PendingLogins, PendingMfa, PENDING_MFA_COOKIE, PENDING_MFA_TTL};
+use crate::public_key::{KeyChallenges, KEY_CHALLENGE_TTL};
+use crate::lockout::{ThrottleStore, Verdict, MAX_FAILED_LOGINS_PER_IP};
+use crate::webauthn::{AssertionResponse, Ceremonies, RegistrationResponse, CEREMONY_TTL};
 use serde_json::json;
 use actix_http::cookie::Cookie;
 use csrf::{AesGcmCsrfProtection, CsrfProtection};
@@ -45,15 +53,104 @@ mod db;
 mod scope;
 mod namespace;
 mod hydra;
+#[cfg(test)]
+mod hydra_mock;
+mod login_policy;
+mod claims;
+mod admin;
+mod csrf_form;
+mod config;
+mod totp;
+mod mfa;
+mod public_key;
+mod webauthn;
+mod lockout;
+mod password;
+mod token;
+mod mailer;
+mod password_reset;
+mod sms;
+mod verification;
+mod magic_link;
+mod phone_otp;
+mod recovery;
+#[cfg(test)]
+mod tests;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct LoginReq {
     identifier: String,
+    identifier_type: Option<IdentifierType>,
     authenticator: String,
-    authenticator_type: authenticator::AuthenticatorType,
-    system: String,
+    authenticator_type: Option<authenticator::AuthenticatorType>,
     _csrf: String,
-    challenge: String
+    challenge: String,
+    remember: Option<String>,
+    register_passkey: Option<String>,
+    regenerate_recovery_codes: Option<String>,
+    submit: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct IdentifierReq {
+    identifier: String,
+    identifier_type: Option<IdentifierType>,
+    _csrf: String,
+    challenge: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for IdentifierReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+impl CsrfProtected for LoginReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TotpReq {
+    code: String,
+    _csrf: String,
+    challenge: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for TotpReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PasskeyReq {
+    credential: Option<String>,
+    _csrf: String,
+    challenge: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for PasskeyReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RecoveryCodesReq {
+    _csrf: String,
+    challenge: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for RecoveryCodesReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -61,117 +158,871 @@ struct HydraLogin {
     challenge: String
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct HydraConsent {
+    consent_challenge: String
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct HydraLogout {
+    logout_challenge: String
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct LogoutReq {
+    challenge: String,
+    _csrf: String,
+    submit: String
+}
+
+impl CsrfProtected for LogoutReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+const CONSENT_REMEMBER_FOR: i32 = 0;
+
+// Used for "remember me" logins when the System does not set its own remember_for
+const DEFAULT_LOGIN_REMEMBER_FOR: i32 = 3600;
+
+// When false, /logout accepts the challenge straight away instead of asking the user first
+const CONFIRM_LOGOUT: bool = true;
+
+// Every cookie travs sets for a browser session, cleared once Hydra accepts a logout
+const SESSION_COOKIES: [&str; 1] = ["_csrf"];
+
 #[derive(Clone)]
 struct AppData<'a> {
     csrf_generator: web::Data<std::sync::Mutex<csrf::AesGcmCsrfProtection>>,
-    hb: web::Data<Handlebars<'a>>
+    hb: web::Data<Handlebars<'a>>,
+    hydra: web::Data<HydraClient>,
+    login_attempts: web::Data<LoginAttempts>,
+    pending_mfa: web::Data<PendingLogins>,
+    key_challenges: web::Data<KeyChallenges>,
+    webauthn_ceremonies: web::Data<Ceremonies>,
+    admin_token: Option<String>,
+    trust_forwarded_for: bool,
+    mailer: Option<std::sync::Arc<dyn mailer::Mailer>>,
+    sms: Option<std::sync::Arc<dyn sms::SmsGateway>>,
+    public_url: String
 }
 
-async fn login_form(query: web::Query<HydraLogin>, data: web::Data<AppData<'_>>) -> HttpResponse {
-    let generator = data.csrf_generator.lock().unwrap();
-    let challenge = query.clone().challenge;
-    let (token, cookie) = generator.generate_token_pair(None, 3600).unwrap();
-    let token_str = token.b64_string();
-    let cookie_str = cookie.b64_string();
+fn session_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build(name, value).path("/").http_only(true).finish()
+}
+
+// The System a login belongs to is derived from the Hydra OAuth client, never from the browser
+fn system_for_client(client_id: &str) -> Result<Option<System>, Error> {
+    SystemStore::find_by_client_id(client_id, vec![
+        "uid".to_string(),
+        "guid".to_string(),
+        "claim_mappings".to_string(),
+        "remember_for".to_string(),
+        "require_mfa".to_string(),
+        "require_verified_identifier".to_string()
+    ])
+        .map_err(error::ErrorInternalServerError)
+}
 
-    drop(generator);
+async fn login_form(query: web::Query<HydraLogin>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = query.clone().challenge;
 
-    let resp: HydraLoginResponse = serde_json::from_str(Hydra::get_login_request(challenge.clone()).await.unwrap().as_str()).unwrap();
+    let resp = data.hydra.get_login_request(&challenge).await?;
 
+    // Hydra already authenticated this subject, so the browser only needs to be sent back
     if resp.skip {
         let accept_login = HydraAcceptLoginRequest {
             subject: resp.subject.clone(),
             remember: false,
-            remember_for: 3600
+            remember_for: 0
         };
 
-        let resp = Hydra::accept_login_request(challenge.clone(), serde_json::to_string(&accept_login).unwrap()).await.unwrap();
+        let resp = data.hydra.accept_login_request(&challenge, &accept_login).await?;
 
-        println!("{:?}", resp);
-
-        return HttpResponse::Ok().finish()
+        return Ok(hydra_redirect(resp))
+    }
 
-        // HttpResponse::Found().header(actix_web::http::header::LOCATION, resp)
+    if system_for_client(&resp.client.client_id)?.is_none() {
+        return reject_login(&data, &challenge, LoginRejection::UnknownClient).await
     }
 
+    let (token, csrf_cookie) = issue_token(&data)?;
+
     let tmpl_data = json!({
         "challenge": challenge.clone(),
-        "csrf_token": token_str
+        "csrf_token": token
     });
 
 
-    let body = data.hb.render("login", &tmpl_data).unwrap();
+    let body = data.hb.render("login", &tmpl_data).map_err(error::ErrorInternalServerError)?;
 
-    let csrf_cookie = Cookie::new("_csrf", cookie_str.clone());
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
 
-    HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body)
+// Template rendering the second login step for an authenticator type, None when travs cannot run that step yet
+fn login_step_template(authenticator_type: &AuthenticatorType) -> Option<&'static str> {
+    match authenticator_type {
+        AuthenticatorType::email_password
+        | AuthenticatorType::phone_password
+        | AuthenticatorType::username_password => Some("login_password"),
+        AuthenticatorType::public_key_authentication => Some("login_key"),
+        AuthenticatorType::webauthn => Some("login_webauthn"),
+        AuthenticatorType::email_link => Some("login_link"),
+        AuthenticatorType::phone_otp => Some("login_phone_otp"),
+        AuthenticatorType::totp
+        | AuthenticatorType::recovery_code => None
+    }
 }
 
-async fn login(item: web::Form<LoginReq>) -> HttpResponse {
-    println!("REQUEST POST: {:?}", item);
+async fn login_identifier(item: CsrfForm<IdentifierReq>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
     let challenge = item.challenge.clone();
 
-    let result = authenticator::AuthenticatorStore::login(
-        Authenticator::new().authenticator_type(item.authenticator_type.clone()).value(item.authenticator.clone()),
-        Identifier::new().identifier_type(IdentifierType::email).value(item.identifier.clone()),
-        System::new().guid(item.system.clone())
-    );
+    if item.submit.as_deref() == Some("cancel") {
+        return reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    let login_request = data.hydra.get_login_request(&challenge).await?;
+    let system = match system_for_client(&login_request.client.client_id)? {
+        Some(system) => system,
+        None => return reject_login(&data, &challenge, LoginRejection::UnknownClient).await
+    };
+
+    let identifier_type = item.identifier_type.clone().unwrap_or_else(|| IdentifierType::infer(&item.identifier));
+    let identifier = Identifier::new().identifier_type(identifier_type.clone()).value(item.identifier.clone());
+    let available = AuthenticatorStore::find_types_by_identifier_system(
+        &identifier,
+        &system
+    ).map_err(error::ErrorInternalServerError)?
+        .into_iter()
+        .filter(|t| login_step_template(t).is_some())
+        .collect::<Vec<AuthenticatorType>>();
 
-    if result.is_err() || !result.ok().unwrap() {
-        return HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish()
+    // Unknown identifiers still get a password step so the form does not reveal which accounts exist
+    let authenticator_type = available.get(0).cloned().unwrap_or_else(|| AuthenticatorType::password_for(&identifier_type));
+    let alternatives: Vec<AuthenticatorType> = available.iter().skip(1).cloned().collect();
+
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let mut tmpl_data = json!({
+        "challenge": challenge.clone(),
+        "csrf_token": token,
+        "identifier": item.identifier.clone(),
+        "identifier_type": identifier_type,
+        "authenticator_type": authenticator_type,
+        "alternatives": alternatives
+    });
+
+    if authenticator_type == AuthenticatorType::public_key_authentication {
+        tmpl_data["message"] = json!(data.key_challenges.issue(&challenge));
     }
 
-    println!("Received challenge {} and creds are good", challenge);
-    // let generator = data.lock().unwrap();
-    // let (token, cookie) = generator.generate_token_pair(None, 3600).unwrap();
-    // let token_str = token.b64_string();
-    // let cookie_str = cookie.b64_string();
-    // let challenge = query.clone().challenge;
-    //
-    // println!("{}", challenge.clone());
-    //
-    // let resp: HydraLoginResponse = serde_json::from_str(Hydra::get_login_request(challenge).await.unwrap().as_str()).unwrap();
-    //
-    // println!("{:?}", resp);
-    //
-    // let inner = item.into_inner();
-    //
+    if authenticator_type == AuthenticatorType::webauthn {
+        let credentials: Vec<webauthn::StoredCredential> = AuthenticatorStore::find_webauthn_credentials(&identifier, &system)
+            .map_err(error::ErrorInternalServerError)?
+            .into_iter()
+            .map(|(_, c)| c)
+            .collect();
+        let options = webauthn::request_options(&data.webauthn_ceremonies.issue(&challenge), &credentials)
+            .map_err(error::ErrorInternalServerError)?;
+        tmpl_data["options"] = json!(options.to_string());
+    }
+
+    // A code goes out as soon as the phone step is shown; asking again just comes back through here
+    if authenticator_type == AuthenticatorType::phone_otp {
+        let identifier_uid = IdentifierStore::find_by_type_value(&identifier_type, &format!("^{}$", regex::escape(&item.identifier)), vec!["uid".to_string()])
+            .map_err(error::ErrorInternalServerError)?
+            .and_then(|i| i.uid)
+            .ok_or(error::ErrorInternalServerError("Identifier does not exist"))?;
+        tmpl_data["sent"] = json!(phone_otp::send_code(&data, &identifier_uid, &item.identifier).await?);
+    }
+
+    let template = login_step_template(&authenticator_type).unwrap_or("login_password");
+    let body = data.hb.render(template, &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+async fn reject_login(data: &AppData<'_>, challenge: &str, rejection: LoginRejection) -> Result<HttpResponse, Error> {
+    data.login_attempts.clear(challenge);
+    data.pending_mfa.clear(challenge);
+    data.key_challenges.take(challenge);
+    data.webauthn_ceremonies.take(challenge);
+    let resp = data.hydra.reject_login_request(challenge, &rejection.to_reject_request()).await?;
+
+    Ok(hydra_redirect(resp))
+}
+
+// Address failed logins are throttled by, empty when the connection has no peer address
+fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.parse::<std::net::SocketAddr>().map(|a| a.ip().to_string()).unwrap_or_else(|_| ip.to_string())
+        }
+    }
+    req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default()
+}
+
+async fn login(item: CsrfForm<LoginReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    let ip = client_ip(&req, data.trust_forwarded_for);
+    let throttle_key = ThrottleStore::ip_key(&ip);
+    // Only the lockout applies per address; backing off from every failure would hold up everyone
+    // sharing it, so that is left to the per-identifier counters
+    if !ip.is_empty() {
+        if let Verdict::Locked(_) = ThrottleStore::check(&throttle_key).map_err(error::ErrorInternalServerError)? {
+            return reject_login(&data, &challenge, LoginRejection::Throttled).await
+        }
+    }
+
+    let login_request = data.hydra.get_login_request(&challenge).await?;
+    let system = match system_for_client(&login_request.client.client_id)? {
+        Some(system) => system,
+        None => return reject_login(&data, &challenge, LoginRejection::UnknownClient).await
+    };
+
+    // Without explicit types the identifier's shape decides, and the password authenticator follows from it
+    let identifier_type = item.identifier_type.clone().unwrap_or_else(|| IdentifierType::infer(&item.identifier));
+    let authenticator_type = item.authenticator_type.clone().unwrap_or_else(|| AuthenticatorType::password_for(&identifier_type));
+
+    if !AuthenticatorStore::_validate_authenticator_type(&authenticator_type, &identifier_type) {
+        return Err(error::ErrorBadRequest(AuthenticatorError::AuthTypeIdentTypeMisMatch()))
+    }
+
+    let presented = Authenticator::new().authenticator_type(authenticator_type.clone()).value(item.authenticator.clone());
+    let identifier = Identifier::new().identifier_type(identifier_type.clone()).value(item.identifier.clone());
+
+    // Key logins sign the nonce issued for this challenge, which is consumed whether or not the signature holds
+    let result = match authenticator_type {
+        AuthenticatorType::public_key_authentication => match data.key_challenges.take(&challenge) {
+            Some(message) => AuthenticatorStore::login_signature(presented, identifier, system.clone(), message.as_bytes()),
+            None => Ok(false)
+        },
+        // Passkey logins post the PublicKeyCredential as JSON in place of a password
+        AuthenticatorType::webauthn => match (data.webauthn_ceremonies.take(&challenge), serde_json::from_str::<AssertionResponse>(&item.authenticator)) {
+            (Some(ceremony), Ok(assertion)) => AuthenticatorStore::login_webauthn(identifier, system.clone(), &ceremony, &assertion),
+            _ => Ok(false)
+        },
+        AuthenticatorType::phone_otp => AuthenticatorStore::login_phone_otp(presented, identifier, system.clone()),
+        _ => AuthenticatorStore::login(presented, identifier, system.clone())
+    };
+
+    match result {
+        Ok(true) => {},
+        Err(ref e) if matches!(e.downcast_ref::<AuthenticatorError>(), Some(AuthenticatorError::Locked())) => {
+            return reject_login(&data, &challenge, LoginRejection::Locked).await
+        },
+        // Attempts during a backoff never reach the credential, so they are not failures of the address or the challenge
+        Err(ref e) if matches!(e.downcast_ref::<AuthenticatorError>(), Some(AuthenticatorError::Throttled(_))) => {
+            return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+        },
+        _ => {
+            if !ip.is_empty() {
+                ThrottleStore::record_failure(&throttle_key, MAX_FAILED_LOGINS_PER_IP).map_err(error::ErrorInternalServerError)?;
+            }
+            if let Some(rejection) = data.login_attempts.record_failure(&challenge) {
+                return reject_login(&data, &challenge, rejection).await
+            }
+            return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+        }
+    }
+
+    data.login_attempts.clear(&challenge);
+
+    first_factor_passed(
+        &data,
+        &challenge,
+        &system,
+        &Identifier::new().identifier_type(identifier_type.clone()).value(item.identifier.clone()),
+        item.remember.is_some(),
+        item.register_passkey.is_some(),
+        item.regenerate_recovery_codes.is_some()
+    ).await
+}
+
+// Shared by every first factor once it has been verified for the identifier
+async fn first_factor_passed(
+    data: &AppData<'_>,
+    challenge: &str,
+    system: &System,
+    identifier: &Identifier,
+    remember: bool,
+    register_passkey: bool,
+    regenerate_recovery_codes: bool
+) -> Result<HttpResponse, Error> {
+    let identifier_type = identifier.identifier_type.clone().ok_or(error::ErrorInternalServerError("Identifier is missing a type"))?;
+    let identifier_value = identifier.value.clone().ok_or(error::ErrorInternalServerError("Identifier is missing a value"))?;
+    let remember_for = system.remember_for.unwrap_or(DEFAULT_LOGIN_REMEMBER_FOR);
+    let system_guid = system.guid.clone().ok_or(error::ErrorInternalServerError("System is missing a guid"))?;
+    let require_mfa = system.require_mfa.unwrap_or(false);
+    let require_verified_identifier = system.require_verified_identifier.unwrap_or(false);
+
+    // Hydra subjects are Entity guids so consent can resolve claims whatever identifier was used
+    let entity = EntityStore::find_by_identifier(
+        identifier_type.clone(),
+        format!("^{}$", regex::escape(&identifier_value)),
+        vec!["uid".to_string(), "guid".to_string()]
+    ).map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Authenticated identifier has no entity"))?;
+    let subject = entity.guid.clone().ok_or(error::ErrorInternalServerError("Authenticated identifier has no entity"))?;
+
+    let has_totp = !AuthenticatorStore::find_by_entity_system(&AuthenticatorType::totp, &subject, &system_guid, vec!["uid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .is_empty();
+
+    let found = IdentifierStore::find_by_type_value(&identifier_type, &format!("^{}$", regex::escape(&identifier_value)), vec!["uid".to_string(), "verified".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Authenticated identifier does not exist"))?;
+    let verify_identifier = require_verified_identifier && identifier_type.verifiable() && !found.verified.unwrap_or(false);
+
+    // The first factor only completes the login when no second factor is enrolled or required,
+    // the identifier needs no confirmation and no passkey registration was asked for
+    if has_totp || require_mfa || register_passkey || verify_identifier {
+        let mut pending = PendingMfa::new(
+            subject,
+            entity.uid.clone().ok_or(error::ErrorInternalServerError("Entity is missing a uid"))?,
+            found.uid.clone().ok_or(error::ErrorInternalServerError("Identifier is missing a uid"))?,
+            identifier_value,
+            system_guid
+        )
+            .remember(remember, remember_for)
+            .register_passkey(register_passkey)
+            .verify_identifier(verify_identifier)
+            // Recovery codes only stand in for a second factor, so they are only handed out alongside one
+            .regenerate_recovery_codes(regenerate_recovery_codes && has_totp)
+            .verified(!has_totp && !require_mfa);
+        if !has_totp && require_mfa {
+            pending = pending.enrolling_secret(totp::generate_secret());
+        }
+
+        // The rest of the login only continues in the browser that passed the first factor
+        let nonce_cookie = session_cookie(PENDING_MFA_COOKIE, pending.browser_nonce.clone());
+        let mut resp = if pending.verified {
+            continue_login(data, challenge, pending).await?
+        } else {
+            data.pending_mfa.insert(challenge, pending.clone());
+            totp_step(data, challenge, &pending, false)?
+        };
+        resp.add_cookie(&nonce_cookie).map_err(error::ErrorInternalServerError)?;
+        return Ok(resp)
+    }
+
+    accept_login(data, challenge, subject, remember, remember_for).await
+}
+
+// The pending login of the challenge, as long as this is the browser that passed its first factor
+fn pending_login(data: &AppData<'_>, challenge: &str, req: &HttpRequest) -> Option<PendingMfa> {
+    let nonce = req.cookie(PENDING_MFA_COOKIE)?;
+    data.pending_mfa.get(challenge, nonce.value())
+}
+
+async fn accept_login(data: &AppData<'_>, challenge: &str, subject: String, remember: bool, remember_for: i32) -> Result<HttpResponse, Error> {
     let accept_login = HydraAcceptLoginRequest {
-        subject: item.identifier.clone(),
-        remember: false,
-        remember_for: 3600
+        subject,
+        remember,
+        remember_for
     };
 
-    let resp = Hydra::accept_login_request(item.challenge.clone(), serde_json::to_string(&accept_login).unwrap()).await.unwrap();
-    //
-    // println!("{:?}", resp);
-    //
-    // let result = authenticator::AuthenticatorStore::login(
-    //     Authenticator::new().authenticator_type(inner.clone().authenticator_type).value(inner.clone().authenticator),
-    //     Identifier::new().identifier_type(IdentifierType::email).value(inner.clone().identifier),
-    //     System::new().guid(inner.clone().system)
-    // );
-    //
-    // if result.is_err() || !result.ok().unwrap() {
-    //     return HttpResponse::Unauthorized().finish()
-    // }
-    //
-    // let csrf_cookie = Cookie::new("_csrf", cookie_str.clone());
-    // let token_cookie = Cookie::new("_csrf_token", token_str.clone());
-    //
-    // drop(generator);
-    //
-    let resp_json: serde_json::Value = serde_json::from_str(resp.clone().as_str()).unwrap();
-    //
-    // let client: reqwest::Client = reqwest::Client::new();
-    //
-    // let redirect_that_bitch = client.get(resp_json["redirect_to"].as_str().unwrap()).header("csrf", cookie_str).send().await.unwrap();
-    //
-    // println!("{:?}", redirect_that_bitch);
+    let resp = data.hydra.accept_login_request(challenge, &accept_login).await?;
+
+    Ok(hydra_redirect(resp))
+}
+
+// Runs what is left once every required factor is verified: identifier confirmation, passkey registration,
+// then new recovery codes
+async fn continue_login(data: &AppData<'_>, challenge: &str, pending: PendingMfa) -> Result<HttpResponse, Error> {
+    if pending.verify_identifier {
+        data.pending_mfa.insert(challenge, pending.clone());
+        return verification::start(data, challenge, &pending).await
+    }
+    if pending.register_passkey {
+        data.pending_mfa.insert(challenge, pending.clone());
+        return passkey_step(data, challenge, &pending, false)
+    }
+    if pending.regenerate_recovery_codes {
+        let pending = pending.regenerate_recovery_codes(false);
+        data.pending_mfa.insert(challenge, pending.clone());
+        return recovery_codes_step(data, challenge, &pending).await
+    }
+
+    data.pending_mfa.clear(challenge);
+
+    accept_login(data, challenge, pending.subject.clone(), pending.remember, pending.remember_for).await
+}
+
+// Renders the TOTP code prompt, or the enrollment page with a provisioning QR code when no secret is stored yet
+fn totp_step(data: &AppData<'_>, challenge: &str, pending: &PendingMfa, failed: bool) -> Result<HttpResponse, Error> {
+    let (token, csrf_cookie) = issue_token(data)?;
+
+    let mut tmpl_data = json!({
+        "challenge": challenge,
+        "csrf_token": token,
+        "identifier": pending.identifier.clone(),
+        "failed": failed
+    });
+
+    let template = match &pending.enrolling_secret {
+        Some(secret) => {
+            let uri = totp::provisioning_uri(&pending.identifier, secret).map_err(error::ErrorInternalServerError)?;
+            tmpl_data["qr_code"] = json!(totp::qr_svg(&uri).map_err(error::ErrorInternalServerError)?);
+            tmpl_data["provisioning_uri"] = json!(uri);
+            "totp_enroll"
+        },
+        None => "login_totp"
+    };
+
+    let body = data.hb.render(template, &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+async fn login_totp(item: CsrfForm<TotpReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    // Without a pending first factor the browser has to start again from the identifier step
+    let pending = match pending_login(&data, &challenge, &req) {
+        Some(pending) => pending,
+        None => return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+    };
 
-    return HttpResponse::Found().header(actix_web::http::header::LOCATION, resp_json["redirect_to"].as_str().unwrap()).finish()
+    // A code from the new secret proves the authenticator app is set up, so the secret can be stored
+    // along with the step that was used, and the first recovery codes handed out
+    let verified = match &pending.enrolling_secret {
+        Some(secret) => match totp::matched_step(secret, &item.code, totp::now()).map_err(error::ErrorInternalServerError)? {
+            Some(step) => {
+                let sealed = totp::encrypt_secret(secret).map_err(error::ErrorInternalServerError)?;
+                enroll_authenticator(&pending, Authenticator::new().authenticator_type(AuthenticatorType::totp).value(sealed).last_used_step(step as i64))?;
+                true
+            },
+            None => false
+        },
+        None => {
+            let (subject, system_guid, code) = (pending.subject.clone(), pending.system_guid.clone(), item.code.clone());
+            // Falling back to recovery codes can take ten argon2 runs, so the check goes to the blocking pool
+            match web::block(move || AuthenticatorStore::verify_second_factor(&subject, &system_guid, &code)).await {
+                Ok(verified) => verified,
+                Err(BlockingError::Error(ref e)) if matches!(e.downcast_ref::<AuthenticatorError>(), Some(AuthenticatorError::Locked())) => {
+                    return reject_login(&data, &challenge, LoginRejection::Locked).await
+                },
+                // Backoff is already counted on the authenticator, so the challenge is not charged again
+                Err(BlockingError::Error(ref e)) if matches!(e.downcast_ref::<AuthenticatorError>(), Some(AuthenticatorError::Throttled(_))) => {
+                    return totp_step(&data, &challenge, &pending, true)
+                },
+                Err(e) => return Err(error::ErrorInternalServerError(e))
+            }
+        }
+    };
+
+    if !verified {
+        if let Some(rejection) = data.login_attempts.record_failure(&challenge) {
+            return reject_login(&data, &challenge, rejection).await
+        }
+        return totp_step(&data, &challenge, &pending, true)
+    }
+
+    let mut pending = pending.verified(true);
+    if pending.enrolling_secret.is_some() {
+        pending = pending.regenerate_recovery_codes(true);
+    }
+
+    data.login_attempts.clear(&challenge);
+
+    continue_login(&data, &challenge, pending).await
 }
 
+// Links a newly enrolled authenticator to the entity, identifier and system of the pending login
+fn enroll_authenticator(pending: &PendingMfa, authenticator: Authenticator) -> Result<(), Error> {
+    let entity = EntityStore::find_by_uid(&pending.entity_uid, vec!["uid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Entity does not exist"))?;
+    let identifier = IdentifierStore::find_by_uid(&pending.identifier_uid, vec!["uid".to_string(), "identifier_type".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Identifier does not exist"))?;
+    let system = SystemStore::find_by_guid(&pending.system_guid, vec!["uid".to_string(), "guid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("System does not exist"))?;
+
+    AuthenticatorStore::create(
+        authenticator
+            .add_entity(entity)
+            .add_identifier(identifier)
+            .add_system(system),
+        vec!["uid".to_string()]
+    ).map_err(error::ErrorInternalServerError)?;
+
+    Ok(())
+}
+
+// Renders the passkey registration page with PublicKeyCredentialCreationOptions for a fresh ceremony
+fn passkey_step(data: &AppData<'_>, challenge: &str, pending: &PendingMfa, failed: bool) -> Result<HttpResponse, Error> {
+    let identifier = IdentifierStore::find_by_uid(&pending.identifier_uid, vec!["identifier_type".to_string(), "value".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Identifier does not exist"))?;
+    let registered: Vec<webauthn::StoredCredential> = AuthenticatorStore::find_webauthn_credentials(&identifier, &System::new().guid(pending.system_guid.clone()))
+        .map_err(error::ErrorInternalServerError)?
+        .into_iter()
+        .map(|(_, c)| c)
+        .collect();
+    let options = webauthn::creation_options(&data.webauthn_ceremonies.issue(challenge), &pending.subject, &pending.identifier, &registered)
+        .map_err(error::ErrorInternalServerError)?;
+
+    let (token, csrf_cookie) = issue_token(data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge,
+        "csrf_token": token,
+        "identifier": pending.identifier.clone(),
+        "options": options.to_string(),
+        "failed": failed
+    });
+
+    let body = data.hb.render("webauthn_register", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+async fn register_passkey(item: CsrfForm<PasskeyReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    // Registration is only offered once every required factor of this login has been verified
+    // and the identifier has been confirmed
+    let pending = match pending_login(&data, &challenge, &req) {
+        Some(pending) if pending.verified && !pending.verify_identifier => pending,
+        _ => return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+    };
+
+    if item.submit.as_deref() != Some("skip") {
+        let registration = item.credential.as_ref()
+            .and_then(|c| serde_json::from_str::<RegistrationResponse>(c).ok());
+        let stored = match (data.webauthn_ceremonies.take(&challenge), registration) {
+            (Some(ceremony), Some(registration)) => webauthn::finish_registration(&ceremony, &registration).ok(),
+            _ => None
+        };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return passkey_step(&data, &challenge, &pending, true)
+        };
+        enroll_authenticator(&pending, Authenticator::new().authenticator_type(AuthenticatorType::webauthn).value(stored.to_value().map_err(error::ErrorInternalServerError)?))?;
+    }
+
+    continue_login(&data, &challenge, pending.register_passkey(false)).await
+}
+
+// Replaces the entity's recovery codes on the system with a new batch and shows them once.
+// Only the argon2 hashes are stored, so this page is the only place the codes can be read.
+async fn recovery_codes_step(data: &AppData<'_>, challenge: &str, pending: &PendingMfa) -> Result<HttpResponse, Error> {
+    let codes = recovery::generate_codes();
+    // Ten argon2 runs would stall the worker, so they go to the blocking pool
+    let normalized: Vec<String> = codes.iter().map(|c| recovery::normalize(c)).collect();
+    let hashes = web::block(move || {
+        normalized.iter()
+            .map(|code| password::policy().hash(code.as_bytes()))
+            .collect::<Result<Vec<String>, argon2::Error>>()
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    let entity = EntityStore::find_by_uid(&pending.entity_uid, vec!["uid".to_string(), "guid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Entity does not exist"))?;
+    let identifier = IdentifierStore::find_by_uid(&pending.identifier_uid, vec!["uid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Identifier does not exist"))?;
+    let system = SystemStore::find_by_guid(&pending.system_guid, vec!["uid".to_string(), "guid".to_string()])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("System does not exist"))?;
+    AuthenticatorStore::replace_recovery_codes(&entity, &identifier, &system, hashes).map_err(error::ErrorInternalServerError)?;
+
+    let (token, csrf_cookie) = issue_token(data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge,
+        "csrf_token": token,
+        "identifier": pending.identifier.clone(),
+        "codes": codes
+    });
+
+    let body = data.hb.render("recovery_codes", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string())
+        .header(actix_web::http::header::CACHE_CONTROL, "no-store")
+        .body(body))
+}
+
+async fn acknowledge_recovery_codes(item: CsrfForm<RecoveryCodesReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    let pending = match pending_login(&data, &challenge, &req) {
+        Some(pending) if pending.verified => pending,
+        _ => return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+    };
+
+    continue_login(&data, &challenge, pending).await
+}
+
+fn hydra_redirect(resp: HydraCompletedRequest) -> HttpResponse {
+    HttpResponse::Found().header(actix_web::http::header::LOCATION, resp.redirect_to).finish()
+}
+
+fn consent_system(resp: &HydraConsentResponse) -> Result<System, Error> {
+    system_for_client(&resp.client.client_id)?
+        .ok_or(error::ErrorForbidden("The OAuth client is not bound to a travs system"))
+}
+
+// Requested scopes narrowed down to the ones the subject actually holds in the client's system
+fn grantable_scopes(resp: &HydraConsentResponse, system: &System) -> Result<Vec<String>, Error> {
+    let system_uid = system.uid.as_ref().ok_or(error::ErrorInternalServerError("System is missing a uid"))?;
+
+    ScopeStore::filter_granted(&resp.requested_scope, system_uid, &resp.subject).map_err(error::ErrorInternalServerError)
+}
+
+// Consent is refused while the System requires a verified identifier the subject does not have yet,
+// which also covers sessions Hydra remembered from before the requirement was set
+fn identifier_unverified(resp: &HydraConsentResponse, system: &System) -> Result<bool, Error> {
+    if !system.require_verified_identifier.unwrap_or(false) {
+        return Ok(false)
+    }
+    Ok(!verification::subject_verified(&resp.subject).map_err(error::ErrorInternalServerError)?)
+}
+
+async fn reject_unverified_consent(data: &AppData<'_>, challenge: &str) -> Result<HttpResponse, Error> {
+    let reject_consent = HydraRejectRequest {
+        error: "access_denied".to_string(),
+        error_description: "The user has not verified an email address or phone number".to_string(),
+        status_code: 403
+    };
+
+    let resp = data.hydra.reject_consent_request(challenge, &reject_consent).await?;
+
+    Ok(hydra_redirect(resp))
+}
+
+fn consent_session(resp: &HydraConsentResponse, system: &System, grant_scope: &[String]) -> Result<HydraConsentSession, Error> {
+    ClaimStore::session_for(&resp.subject, system, grant_scope).map_err(error::ErrorInternalServerError)
+}
+
+async fn consent_form(query: web::Query<HydraConsent>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = query.consent_challenge.clone();
+
+    let resp = data.hydra.get_consent_request(&challenge).await?;
+    let system = consent_system(&resp)?;
+    if identifier_unverified(&resp, &system)? {
+        return reject_unverified_consent(&data, &challenge).await
+    }
+    let grantable = grantable_scopes(&resp, &system)?;
+
+    // Hydra sets skip when the subject already granted this client a remembered consent
+    if resp.skip {
+        let accept_consent = HydraAcceptConsentRequest {
+            session: consent_session(&resp, &system, &grantable)?,
+            grant_scope: grantable,
+            grant_access_token_audience: resp.requested_access_token_audience.clone().unwrap_or_default(),
+            remember: true,
+            remember_for: CONSENT_REMEMBER_FOR
+        };
+
+        let resp = data.hydra.accept_consent_request(&challenge, &accept_consent).await?;
+
+        return Ok(hydra_redirect(resp))
+    }
+
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge.clone(),
+        "csrf_token": token,
+        "client_name": resp.client.client_name.clone(),
+        "client_id": resp.client.client_id.clone(),
+        "subject": resp.subject.clone(),
+        "requested_scope": grantable
+    });
+
+    let body = data.hb.render("consent", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+// The consent form submits one grant_scope pair per checked scope, so it is read as raw pairs
+async fn consent(form: CsrfForm<Vec<(String, String)>>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let fields = form.into_inner();
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+
+    let challenge = field("challenge").ok_or(error::ErrorBadRequest("Field challenge cannot be empty"))?;
+
+    if field("submit").as_deref() != Some("accept") {
+        let reject_consent = HydraRejectRequest {
+            error: "access_denied".to_string(),
+            error_description: "The resource owner denied the request".to_string(),
+            status_code: 403
+        };
+
+        let resp = data.hydra.reject_consent_request(&challenge, &reject_consent).await?;
+
+        return Ok(hydra_redirect(resp))
+    }
+
+    let resp = data.hydra.get_consent_request(&challenge).await?;
+    let system = consent_system(&resp)?;
+    if identifier_unverified(&resp, &system)? {
+        return reject_unverified_consent(&data, &challenge).await
+    }
+    let grantable = grantable_scopes(&resp, &system)?;
+
+    // Only scopes the client asked for and the subject holds can be granted, whatever the browser sent
+    let grant_scope: Vec<String> = fields.iter()
+        .filter(|(k, v)| k == "grant_scope" && grantable.contains(v))
+        .map(|(_, v)| v.clone())
+        .collect();
+
+    let accept_consent = HydraAcceptConsentRequest {
+        session: consent_session(&resp, &system, &grant_scope)?,
+        grant_scope,
+        grant_access_token_audience: resp.requested_access_token_audience.clone().unwrap_or_default(),
+        remember: field("remember").is_some(),
+        remember_for: CONSENT_REMEMBER_FOR
+    };
+
+    let resp = data.hydra.accept_consent_request(&challenge, &accept_consent).await?;
+
+    Ok(hydra_redirect(resp))
+}
+
+async fn accept_logout(hydra: &HydraClient, challenge: &str) -> Result<HttpResponse, Error> {
+    let resp = hydra.accept_logout_request(challenge).await?;
+    let mut redirect = hydra_redirect(resp);
+
+    for name in SESSION_COOKIES.iter() {
+        let mut cookie = session_cookie(*name, String::new());
+        cookie.make_removal();
+        redirect.add_cookie(&cookie).map_err(error::ErrorInternalServerError)?;
+    }
+
+    Ok(redirect)
+}
+
+async fn logout_form(query: web::Query<HydraLogout>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = query.logout_challenge.clone();
+
+    let resp = data.hydra.get_logout_request(&challenge).await?;
+
+    if !CONFIRM_LOGOUT {
+        return accept_logout(&data.hydra, &challenge).await
+    }
+
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge.clone(),
+        "csrf_token": token,
+        "subject": resp.subject.clone()
+    });
+
+    let body = data.hb.render("logout", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+async fn logout(item: CsrfForm<LogoutReq>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit == "accept" {
+        return accept_logout(&data.hydra, &challenge).await
+    }
+
+    let reject_logout = HydraRejectRequest {
+        error: "access_denied".to_string(),
+        error_description: "The user decided to stay logged in".to_string(),
+        status_code: 401
+    };
+
+    data.hydra.reject_logout_request(&challenge, &reject_logout).await?;
+
+    let body = data.hb.render("logout", &json!({ "cancelled": true })).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().body(body))
+}
+
+// Shared by the server and the handler tests, which mount the same routes against a mocked Hydra
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/login")
+            .route(web::post().to(login))
+            .route(web::get().to(login_form)))
+        .service(
+            web::resource("/login/identifier")
+                .route(web::post().to(login_identifier)))
+        .service(
+            web::resource("/login/totp")
+                .route(web::post().to(login_totp)))
+        .service(
+            web::resource("/login/webauthn/register")
+                .route(web::post().to(register_passkey)))
+        .service(
+            web::resource("/login/link")
+                .route(web::post().to(magic_link::send_link))
+                .route(web::get().to(magic_link::follow_link)))
+        .service(
+            web::resource("/login/link/confirm")
+                .route(web::post().to(magic_link::confirm_link)))
+        .service(
+            web::resource("/login/recovery-codes")
+                .route(web::post().to(acknowledge_recovery_codes)))
+        .service(
+            web::resource("/login/verify")
+                .route(web::post().to(verification::login_verify)))
+        .service(
+            web::resource("/verify/identifier")
+                .route(web::get().to(verification::confirm_link)))
+        .service(
+            web::resource("/password/forgot")
+                .route(web::post().to(password_reset::forgot))
+                .route(web::get().to(password_reset::forgot_form)))
+        .service(
+            web::resource("/password/reset")
+                .route(web::post().to(password_reset::reset))
+                .route(web::get().to(password_reset::reset_form)))
+        .service(
+            web::resource("/consent")
+                .route(web::post().to(consent))
+                .route(web::get().to(consent_form)))
+        .service(
+            web::resource("/logout")
+                .route(web::post().to(logout))
+                .route(web::get().to(logout_form)))
+        .service(
+            web::resource("/admin/systems/{guid}/clients")
+                .route(web::post().to(admin::create_client))
+                .route(web::get().to(admin::list_clients)))
+        .service(
+            web::resource("/admin/systems/{guid}/clients/{client_id}")
+                .route(web::put().to(admin::update_client))
+                .route(web::delete().to(admin::delete_client)))
+        .service(
+            web::resource("/admin/entities/{guid}/unlock")
+                .route(web::post().to(admin::unlock_entity)))
+        .service(
+            web::resource("/admin/entities/{guid}/recovery-codes")
+                .route(web::get().to(admin::recovery_code_count)))
+        .service(
+            web::resource("/admin/throttles/ip/{ip}")
+                .route(web::delete().to(admin::clear_ip_throttle)));
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
@@ -191,6 +1042,22 @@ async fn main() -> std::io::Result<()> {
 	// 	scope_type: string @index(exact) .
 	// 	scope: [uid] @reverse .
 	// 	namespace: [uid] @reverse .
+	// 	client_id: [string] @index(exact) .
+	// 	claim_mappings: string .
+	// 	remember_for: int .
+	// 	require_mfa: bool .
+	// 	require_verified_identifier: bool .
+	// 	verified: bool .
+	// 	verified_at: int .
+	// 	verification_sent_at: int .
+	// 	failed_attempts: int .
+	// 	last_failed_at: int .
+	// 	locked_until: int .
+	// 	last_used_step: int .
+	// 	throttle_key: string @index(exact) .
+	// 	token_hash: string @index(exact) .
+	// 	purpose: string @index(exact) .
+	// 	expires_at: int .
     //
 	// 	type Entity {
 	// 		guid
@@ -207,6 +1074,9 @@ async fn main() -> std::io::Result<()> {
 	// 	    authenticator
 	// 		identifier_type
 	// 		value
+	// 		verified
+	// 		verified_at
+	// 		verification_sent_at
 	// 	}
     //
 	// 	type Authenticator {
@@ -214,6 +1084,24 @@ async fn main() -> std::io::Result<()> {
 	// 	    identifier
 	// 	    authenticator_type
 	// 	    value
+	// 	    failed_attempts
+	// 	    last_failed_at
+	// 	    locked_until
+	// 	    last_used_step
+	// 	}
+    //
+	// 	type Token {
+	// 	    token_hash
+	// 	    purpose
+	// 	    expires_at
+	// 	    identifier
+	// 	}
+    //
+	// 	type Throttle {
+	// 	    throttle_key
+	// 	    failed_attempts
+	// 	    last_failed_at
+	// 	    locked_until
 	// 	}
     //
 	// 	type System {
@@ -222,6 +1110,11 @@ async fn main() -> std::io::Result<()> {
 	// 	    entity
 	// 	    authenticator
 	// 	    namespace
+	// 	    client_id
+	// 	    claim_mappings
+	// 	    remember_for
+	// 	    require_mfa
+	// 	    require_verified_identifier
 	// 	}
     //
 	// 	type Namespace {
@@ -254,28 +1147,59 @@ async fn main() -> std::io::Result<()> {
     // let i_res = identifier::IdentifierStore::create(i.clone(), vec!["uid".to_string()]).unwrap();
     // i.uid = Some(i_res.unwrap().uid.unwrap());
     //
-    // let random_u64 = OsRng.next_u64();
-    //
-    // let salt = format!("{:X}", random_u64);
-    //
-    // let config = argon2::Config::default();
     // let start = std::time::Instant::now();
-    // let hash = argon2::hash_encoded("password123".as_bytes(), salt.as_bytes(), &config).unwrap();
+    // let hash = password::policy().hash("password123".as_bytes()).unwrap();
     // println!("Took {}ms to hash", start.elapsed().as_millis());
     // let mut a = authenticator::Authenticator::new().authenticator_type(authenticator::AuthenticatorType::email_password).value(hash.to_string()).add_system(s.clone()).add_identifier(i.clone()).add_entity(e.clone());
     // let a_res = authenticator::AuthenticatorStore::create(a.clone(), vec!["uid".to_string()]).unwrap();
 
-    let generator = web::Data::new(std::sync::Mutex::new((AesGcmCsrfProtection::from_key(*b"01234567012345670123456701234567"))));
+    let config = config::Config::load()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    db::configure(config.dgraph.address.clone());
+
+    let csrf_key = config.csrf_key()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let generator = web::Data::new(std::sync::Mutex::new(AesGcmCsrfProtection::from_key(csrf_key)));
 
     let mut handlebars = Handlebars::new();
     handlebars
-        .register_templates_directory(".html", "./static")
+        .register_templates_directory(".html", &config.template_dir)
         .unwrap();
     let handlebars_ref = web::Data::new(handlebars);
 
+    let hydra = config.hydra_client()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let admin_token = config.admin_token()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mailer = config.mailer()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let sms = config.sms_gateway()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    password::configure(config.password.clone());
+
+    public_key::configure();
+
+    webauthn::configure(config.webauthn.rp_id.clone(), config.webauthn.rp_name.clone(), config.webauthn.origin.clone());
+
+    if let Some(key) = config.totp_key().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))? {
+        totp::configure(key, config.totp.issuer.clone());
+    }
+
     let app_data = AppData {
         hb: handlebars_ref.clone(),
-        csrf_generator: generator.clone()
+        csrf_generator: generator.clone(),
+        hydra: web::Data::new(hydra),
+        login_attempts: web::Data::new(LoginAttempts::new(MAX_LOGIN_ATTEMPTS)),
+        pending_mfa: web::Data::new(PendingLogins::new(PENDING_MFA_TTL)),
+        key_challenges: web::Data::new(KeyChallenges::new(KEY_CHALLENGE_TTL)),
+        webauthn_ceremonies: web::Data::new(Ceremonies::new(CEREMONY_TTL)),
+        admin_token,
+        trust_forwarded_for: config.lockout.trust_forwarded_for,
+        mailer,
+        sms,
+        public_url: config.public_url.trim_end_matches('/').to_string()
     };
 
     let app_data_ref = web::Data::new(app_data);
@@ -284,12 +1208,9 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(app_data_ref.clone())
             .wrap(middleware::Logger::default())
-            .service(
-                web::resource("/login")
-                    .route(web::post().to(login))
-                    .route(web::get().to(login_form)))
+            .configure(routes)
     })
-        .bind("localhost:8087")?
+        .bind(&config.bind_address)?
         .run()
         .await
 }
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use data_encoding::BASE64URL_NOPAD;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+// How long a password-verified login may wait for its second factor before starting over
+pub const PENDING_MFA_TTL: Duration = Duration::from_secs(300);
+// Cookie holding PendingMfa::browser_nonce in the browser that passed the first factor
+pub const PENDING_MFA_COOKIE: &str = "_mfa";
+
+// A login whose first factor succeeded, held until the second factor for the same challenge is verified
+#[derive(Debug, Clone)]
+pub struct PendingMfa {
+    pub subject: String,
+    pub entity_uid: String,
+    pub identifier_uid: String,
+    pub identifier: String,
+    pub system_guid: String,
+    pub remember: bool,
+    pub remember_for: i32,
+    // Set while the entity enrolls its first TOTP authenticator, never sent to the browser except in the provisioning URI
+    pub enrolling_secret: Option<Vec<u8>>,
+    // Every required factor has been verified and only optional steps such as passkey registration remain
+    pub verified: bool,
+    pub register_passkey: bool,
+    // The System requires a verified identifier and the one used for this login has not been confirmed yet
+    pub verify_identifier: bool,
+    // Show a fresh batch of recovery codes before the login completes
+    pub regenerate_recovery_codes: bool,
+    // Challenges travel in URLs and referrers, so the later steps also need this nonce from the
+    // PENDING_MFA_COOKIE of the browser that passed the first factor
+    pub browser_nonce: String,
+    created: Instant
+}
+
+impl PendingMfa {
+    pub fn new(subject: String, entity_uid: String, identifier_uid: String, identifier: String, system_guid: String) -> PendingMfa {
+        PendingMfa {
+            subject,
+            entity_uid,
+            identifier_uid,
+            identifier,
+            system_guid,
+            remember: false,
+            remember_for: 0,
+            enrolling_secret: None,
+            verified: false,
+            register_passkey: false,
+            verify_identifier: false,
+            regenerate_recovery_codes: false,
+            browser_nonce: browser_nonce(),
+            created: Instant::now()
+        }
+    }
+
+    pub fn remember(mut self, remember: bool, remember_for: i32) -> Self {
+        self.remember = remember;
+        self.remember_for = remember_for;
+        self
+    }
+
+    pub fn enrolling_secret(mut self, secret: Vec<u8>) -> Self {
+        self.enrolling_secret = Some(secret);
+        self
+    }
+
+    pub fn verified(mut self, verified: bool) -> Self {
+        self.verified = verified;
+        self
+    }
+
+    pub fn register_passkey(mut self, register_passkey: bool) -> Self {
+        self.register_passkey = register_passkey;
+        self
+    }
+
+    pub fn verify_identifier(mut self, verify_identifier: bool) -> Self {
+        self.verify_identifier = verify_identifier;
+        self
+    }
+
+    pub fn regenerate_recovery_codes(mut self, regenerate_recovery_codes: bool) -> Self {
+        self.regenerate_recovery_codes = regenerate_recovery_codes;
+        self
+    }
+}
+
+fn browser_nonce() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    BASE64URL_NOPAD.encode(&nonce)
+}
+
+// Keyed by Hydra login challenge, like LoginAttempts
+pub struct PendingLogins {
+    ttl: Duration,
+    pending: Mutex<HashMap<String, PendingMfa>>
+}
+
+impl PendingLogins {
+    pub fn new(ttl: Duration) -> PendingLogins {
+        PendingLogins {
+            ttl,
+            pending: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn insert(&self, challenge: &str, p: PendingMfa) {
+        let mut pending = self.pending.lock().unwrap();
+        let ttl = self.ttl;
+        pending.retain(|_, v| v.created.elapsed() < ttl);
+        pending.insert(challenge.to_string(), p);
+    }
+
+    // Only hands the login out to the browser holding its nonce
+    pub fn get(&self, challenge: &str, browser_nonce: &str) -> Option<PendingMfa> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(challenge) {
+            Some(p) if p.created.elapsed() < self.ttl => {
+                let nonce = p.browser_nonce.as_bytes();
+                if nonce.len() == browser_nonce.len() && openssl::memcmp::eq(nonce, browser_nonce.as_bytes()) {
+                    Some(p.clone())
+                } else {
+                    None
+                }
+            },
+            Some(_) => {
+                pending.remove(challenge);
+                None
+            },
+            None => None
+        }
+    }
+
+    pub fn clear(&self, challenge: &str) {
+        self.pending.lock().unwrap().remove(challenge);
+    }
+}
@@ -121,14 +121,12 @@ impl NamespaceStore {
             if exists.is_some() {
                 return Err(NamespaceError::AlreadyExists().into())
             }
-            println!("DEBUG {:?}", a.clone());
             let mut tmp = a.clone();
             let res: HashMap<String, String> = db::save(serde_json::to_vec(&a)?).unwrap().uids;
             for (_, r) in res {
                 tmp.uid = Some(r);
                 break
             }
-            println!("GUID {:?}", a.clone().systems.ok_or(SystemError::Empty())?.get(0).ok_or(SystemError::Empty())?.guid.as_ref().ok_or(SystemError::Empty())?);
             SystemStore::associate_namespace(
                 a.clone().systems.ok_or(SystemError::Empty())?.get(0).ok_or(SystemError::Empty())?.guid.as_ref().ok_or(SystemError::Empty())?,
                 tmp,
@@ -200,14 +198,11 @@ impl NamespaceStore {
             "fields": fields
         });
         let query = reg.render_template(req, template_vars)?;
-        println!("QUERY {}", query);
         let mut vars: HashMap<String, String> = [
             ("$sys_uid".to_string(), system_uid.to_string()),
             ("$name".to_string(), name.to_string()),
         ].iter().cloned().collect();
-        println!("VAR {:?}", vars);
         let res = db::query(query, vars)?;
-        println!("RES {}", std::str::from_utf8(&res.json)?);
         let e: NamespaceRoot = serde_json::from_slice(&res.json)?;
         match e.namespace.len() {
             0 => Ok(None),
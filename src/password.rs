@@ -0,0 +1,139 @@
+use once_cell::sync::OnceCell;
+use serde_derive::{Deserialize, Serialize};
+use data_encoding::BASE64_NOPAD;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum HashVariant {
+    argon2i,
+    argon2d,
+    argon2id
+}
+
+impl HashVariant {
+    fn to_argon2(&self) -> argon2::Variant {
+        match self {
+            HashVariant::argon2i => argon2::Variant::Argon2i,
+            HashVariant::argon2d => argon2::Variant::Argon2d,
+            HashVariant::argon2id => argon2::Variant::Argon2id
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HashVariant::argon2i => "argon2i",
+            HashVariant::argon2d => "argon2d",
+            HashVariant::argon2id => "argon2id"
+        }
+    }
+}
+
+// Parameters every new password hash is created with; stored hashes below them are upgraded on login
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HashPolicy {
+    pub variant: HashVariant,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+    pub salt_length: usize,
+    pub hash_length: u32
+}
+
+impl Default for HashPolicy {
+    fn default() -> Self {
+        HashPolicy {
+            variant: HashVariant::argon2id,
+            memory_kib: 19456,
+            iterations: 2,
+            lanes: 1,
+            salt_length: 16,
+            hash_length: 32
+        }
+    }
+}
+
+static POLICY: OnceCell<HashPolicy> = OnceCell::new();
+
+// Hash of a random password nobody knows, verified against when there is no real hash to check
+static DUMMY_HASH: OnceCell<String> = OnceCell::new();
+
+// The dummy hash is made here so that the first login with an unknown identifier is not the one paying for it
+pub fn configure(policy: HashPolicy) {
+    let _ = POLICY.set(policy);
+    dummy_hash();
+}
+
+pub fn policy() -> &'static HashPolicy {
+    POLICY.get_or_init(HashPolicy::default)
+}
+
+fn dummy_hash() -> &'static str {
+    DUMMY_HASH.get_or_init(|| {
+        let mut password = [0u8; 32];
+        OsRng.fill_bytes(&mut password);
+        policy().hash(&password).unwrap_or_default()
+    })
+}
+
+// Costs the same argon2 run as checking a real password, for logins that have none to check
+pub fn dummy_verify(presented: &[u8]) {
+    let _ = argon2::verify_encoded(dummy_hash(), presented);
+}
+
+impl HashPolicy {
+    fn argon2_config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: self.variant.to_argon2(),
+            version: argon2::Version::Version13,
+            mem_cost: self.memory_kib,
+            time_cost: self.iterations,
+            lanes: self.lanes,
+            thread_mode: argon2::ThreadMode::from_threads(self.lanes),
+            hash_length: self.hash_length,
+            ..argon2::Config::default()
+        }
+    }
+
+    pub fn hash(&self, password: &[u8]) -> Result<String, argon2::Error> {
+        let mut salt = vec![0u8; self.salt_length];
+        OsRng.fill_bytes(&mut salt);
+        argon2::hash_encoded(password, &salt, &self.argon2_config())
+    }
+
+    // Reads the PHC string, e.g. $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>, and compares it to the policy.
+    // Anything that cannot be parsed is treated as outdated.
+    pub fn needs_rehash(&self, encoded: &str) -> bool {
+        let parts: Vec<&str> = encoded.split('$').collect();
+        // Hashes from before argon2 1.3 have no v= segment
+        let (variant, version, params, salt, hash) = match parts.as_slice() {
+            ["", variant, version, params, salt, hash] if version.starts_with("v=") => (*variant, &version[2..], *params, *salt, *hash),
+            ["", variant, params, salt, hash] => (*variant, "16", *params, *salt, *hash),
+            _ => return true
+        };
+        if variant != self.variant.name() || version != "19" {
+            return true
+        }
+        let mut memory_kib = 0;
+        let mut iterations = 0;
+        let mut lanes = 0;
+        for param in params.split(',') {
+            let mut kv = param.splitn(2, '=');
+            let value = kv.nth(1).and_then(|v| v.parse::<u32>().ok()).unwrap_or(0);
+            match param.chars().next() {
+                Some('m') => memory_kib = value,
+                Some('t') => iterations = value,
+                Some('p') => lanes = value,
+                _ => {}
+            }
+        }
+        let salt_length = BASE64_NOPAD.decode(salt.as_bytes()).map(|s| s.len()).unwrap_or(0);
+        let hash_length = BASE64_NOPAD.decode(hash.as_bytes()).map(|h| h.len() as u32).unwrap_or(0);
+        memory_kib < self.memory_kib
+            || iterations < self.iterations
+            || lanes < self.lanes
+            || salt_length < self.salt_length
+            || hash_length < self.hash_length
+    }
+}
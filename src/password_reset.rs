@@ -0,0 +1,189 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::form_urlencoded;
+use crate::AppData;
+use crate::authenticator::{AuthenticatorStore, AuthenticatorType};
+use crate::csrf_form::{CsrfForm, CsrfProtected, issue_token};
+use crate::identifier::{IdentifierStore, IdentifierType};
+use crate::lockout::{FailureState, ThrottleStore, Verdict, MAX_FAILED_LOGINS};
+use crate::mailer::{MailMessage, Mailer};
+use crate::password;
+use crate::token::{TokenPurpose, TokenStore};
+
+pub const RESET_TOKEN_TTL_SECONDS: i64 = 3600;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForgotQuery {
+    challenge: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForgotReq {
+    email: String,
+    challenge: Option<String>,
+    _csrf: String
+}
+
+impl CsrfProtected for ForgotReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResetQuery {
+    token: String,
+    challenge: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResetReq {
+    token: String,
+    password: String,
+    password_confirm: String,
+    challenge: Option<String>,
+    _csrf: String
+}
+
+impl CsrfProtected for ResetReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+fn render(data: &AppData<'_>, template: &str, mut tmpl_data: serde_json::Value) -> Result<HttpResponse, Error> {
+    let (token, csrf_cookie) = issue_token(data)?;
+    tmpl_data["csrf_token"] = json!(token);
+
+    let body = data.hb.render(template, &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+fn render_error(data: &AppData<'_>, message: &str) -> Result<HttpResponse, Error> {
+    let body = data.hb.render("error", &json!({ "message": message })).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::BadRequest().content_type("text/html").body(body))
+}
+
+pub async fn forgot_form(query: web::Query<ForgotQuery>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    render(&data, "password_forgot", json!({
+        "challenge": query.challenge.clone()
+    }))
+}
+
+// Looks the email up, issues a token and mails the link. Runs after the response has gone out.
+fn send_reset_link(mailer: &dyn Mailer, public_url: &str, email: &str, challenge: Option<&str>) -> Result<(), failure::Error> {
+    let identifier = IdentifierStore::find_by_type_value(&IdentifierType::email, &format!("^{}$", regex::escape(email)), vec!["uid".to_string()])?;
+    let identifier_uid = match identifier.and_then(|i| i.uid) {
+        Some(identifier_uid) => identifier_uid,
+        None => return Ok(())
+    };
+    if AuthenticatorStore::find_by_identifier_uid_type(&identifier_uid, &AuthenticatorType::email_password, vec!["uid".to_string()])?.is_empty() {
+        return Ok(())
+    }
+
+    let token = TokenStore::issue(TokenPurpose::password_reset, &identifier_uid, RESET_TOKEN_TTL_SECONDS)?;
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair("token", &token);
+    if let Some(challenge) = challenge {
+        query.append_pair("challenge", challenge);
+    }
+    let link = format!("{}/password/reset?{}", public_url, query.finish());
+    mailer.send(&MailMessage {
+        to: email.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!("Someone asked to reset the password for {}.\n\nFollow this link within an hour to choose a new one:\n{}\n\nIf this was not you, ignore this message and your password stays the same.", email, link)
+    })?;
+    Ok(())
+}
+
+// The response does not depend on whether the email is registered: the lookup and the mail happen in
+// the background, and throttled or failed sends show the same page, so it cannot be used to probe for accounts
+pub async fn forgot(item: CsrfForm<ForgotReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let mailer = data.mailer.clone().ok_or(error::ErrorNotFound("Password reset is not enabled"))?;
+    let email = item.email.trim().to_string();
+    let challenge = item.challenge.clone().filter(|c| !c.is_empty());
+
+    let mut keys = vec![ThrottleStore::reset_email_key(&email)];
+    let ip = crate::client_ip(&req, data.trust_forwarded_for);
+    if !ip.is_empty() {
+        keys.push(ThrottleStore::reset_ip_key(&ip));
+    }
+    let mut allowed = true;
+    for key in keys.iter() {
+        allowed &= ThrottleStore::check(key).map_err(error::ErrorInternalServerError)? == Verdict::Allowed;
+    }
+    // Every request counts, sent or not, so the backoff does not reveal which emails get mail either
+    for key in keys.iter() {
+        ThrottleStore::record_failure(key, MAX_FAILED_LOGINS).map_err(error::ErrorInternalServerError)?;
+    }
+
+    if allowed {
+        let public_url = data.public_url.clone();
+        actix_rt::spawn(async move {
+            let sent = web::block(move || send_reset_link(mailer.as_ref(), &public_url, &email, challenge.as_deref())).await;
+            if let Err(e) = sent {
+                eprintln!("Could not send a password reset link: {}", e);
+            }
+        });
+    }
+
+    render(&data, "password_forgot", json!({
+        "challenge": item.challenge.clone(),
+        "sent": true
+    }))
+}
+
+pub async fn reset_form(query: web::Query<ResetQuery>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    if TokenStore::peek(&TokenPurpose::password_reset, &query.token).map_err(error::ErrorInternalServerError)?.is_none() {
+        return render_error(&data, "This password reset link is invalid or has expired. Ask for a new one.")
+    }
+
+    render(&data, "password_reset", json!({
+        "token": query.token.clone(),
+        "challenge": query.challenge.clone()
+    }))
+}
+
+pub async fn reset(item: CsrfForm<ResetReq>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let problem = if item.password != item.password_confirm {
+        Some("The passwords do not match.".to_string())
+    } else if item.password.chars().count() < MIN_PASSWORD_LENGTH {
+        Some(format!("The password must be at least {} characters long.", MIN_PASSWORD_LENGTH))
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return render(&data, "password_reset", json!({
+            "token": item.token.clone(),
+            "challenge": item.challenge.clone(),
+            "problem": problem
+        }))
+    }
+
+    let identifier_uid = match TokenStore::consume(&TokenPurpose::password_reset, &item.token).map_err(error::ErrorInternalServerError)? {
+        Some(identifier_uid) => identifier_uid,
+        None => return render_error(&data, "This password reset link is invalid or has expired. Ask for a new one.")
+    };
+    // Older links for the identifier could otherwise reset the new password again
+    TokenStore::revoke(&TokenPurpose::password_reset, &identifier_uid).map_err(error::ErrorInternalServerError)?;
+
+    let presented = item.password.clone();
+    let hash = web::block(move || password::policy().hash(presented.as_bytes())).await.map_err(error::ErrorInternalServerError)?;
+    let authenticators = AuthenticatorStore::find_by_identifier_uid_type(&identifier_uid, &AuthenticatorType::email_password, vec!["uid".to_string()])
+        .map_err(error::ErrorInternalServerError)?;
+    for a in authenticators {
+        let uid = a.uid.ok_or(error::ErrorInternalServerError("Authenticator is missing a uid"))?;
+        AuthenticatorStore::set_value(&uid, hash.clone(), vec!["uid".to_string()]).map_err(error::ErrorInternalServerError)?;
+        // Proving control of the mailbox also lifts any lockout on the password
+        AuthenticatorStore::set_failure_state(&uid, &FailureState::default()).map_err(error::ErrorInternalServerError)?;
+    }
+
+    render(&data, "password_reset", json!({
+        "challenge": item.challenge.clone(),
+        "done": true
+    }))
+}
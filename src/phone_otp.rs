@@ -0,0 +1,28 @@
+use actix_web::{error, Error};
+use crate::AppData;
+use crate::lockout::{ThrottleStore, Verdict, MAX_FAILED_LOGINS};
+use crate::sms::SmsMessage;
+use crate::token::{TokenPurpose, TokenStore};
+
+pub const CODE_TTL_SECONDS: i64 = 300;
+
+// Texts a fresh login code, replacing any earlier one. Returns false without sending while the
+// identifier's sends are throttled, see ThrottleStore::sms_key.
+pub async fn send_code(data: &AppData<'_>, identifier_uid: &str, phone: &str) -> Result<bool, Error> {
+    let gateway = data.sms.as_ref().ok_or(error::ErrorInternalServerError("No SMS gateway is configured for phone codes"))?;
+    let key = ThrottleStore::sms_key(identifier_uid);
+    if ThrottleStore::check(&key).map_err(error::ErrorInternalServerError)? != Verdict::Allowed {
+        return Ok(false)
+    }
+
+    let code = TokenStore::issue_code(TokenPurpose::phone_otp, identifier_uid, CODE_TTL_SECONDS)
+        .map_err(error::ErrorInternalServerError)?;
+    gateway.send(&SmsMessage {
+        to: phone.to_string(),
+        body: format!("Your travs login code is {}. It expires in 5 minutes.", code)
+    }).await.map_err(error::ErrorInternalServerError)?;
+
+    ThrottleStore::record_failure(&key, MAX_FAILED_LOGINS).map_err(error::ErrorInternalServerError)?;
+
+    Ok(true)
+}
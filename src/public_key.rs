@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use failure_derive::*;
+use data_encoding::BASE64;
+use once_cell::sync::OnceCell;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+// How long a login nonce may be signed before the browser or CLI has to ask for a new one
+pub const KEY_CHALLENGE_TTL: Duration = Duration::from_secs(300);
+const NONCE_BYTES: usize = 32;
+
+#[derive(Debug, Fail)]
+pub enum PublicKeyError {
+    #[fail(display = "Registered public key is not a PEM encoded Ed25519 or ECDSA key")]
+    Unsupported(),
+    #[fail(display = "Signature is not valid base64")]
+    Encoding(),
+    #[fail(display = "Public key crypto operation failed: {}", _0)]
+    Crypto(String)
+}
+
+impl From<openssl::error::ErrorStack> for PublicKeyError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        PublicKeyError::Crypto(e.to_string())
+    }
+}
+
+// PEM of a P-256 key nobody holds the private half of, verified against for unknown identifiers
+static DUMMY_KEY: OnceCell<String> = OnceCell::new();
+
+// Makes the dummy key at startup so the first login with an unknown identifier does not pay for it
+pub fn configure() {
+    dummy_key();
+}
+
+fn dummy_key() -> &'static str {
+    DUMMY_KEY.get_or_init(|| {
+        EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+            .and_then(|group| EcKey::generate(&group))
+            .and_then(|key| key.public_key_to_pem())
+            .map(|pem| String::from_utf8_lossy(&pem).to_string())
+            .unwrap_or_default()
+    })
+}
+
+// Costs the same signature check as a registered key, for logins that have none to check against
+pub fn dummy_verify(message: &[u8], signature: &str) {
+    let _ = verify_signature(dummy_key(), message, signature);
+}
+
+// ECDSA signatures are DER encoded over the digest matching the curve, Ed25519 signs the raw message
+fn ecdsa_digest(key: &PKey<Public>) -> Result<MessageDigest, PublicKeyError> {
+    let curve = key.ec_key()?.group().curve_name().ok_or(PublicKeyError::Unsupported())?;
+    match curve {
+        Nid::X9_62_PRIME256V1 => Ok(MessageDigest::sha256()),
+        Nid::SECP384R1 => Ok(MessageDigest::sha384()),
+        Nid::SECP521R1 => Ok(MessageDigest::sha512()),
+        _ => Err(PublicKeyError::Unsupported())
+    }
+}
+
+pub fn verify_signature(public_key_pem: &str, message: &[u8], signature: &str) -> Result<bool, PublicKeyError> {
+    let key = PKey::public_key_from_pem(public_key_pem.trim().as_bytes()).map_err(|_| PublicKeyError::Unsupported())?;
+    let signature = BASE64.decode(signature.trim().as_bytes()).map_err(|_| PublicKeyError::Encoding())?;
+    let verified = match key.id() {
+        Id::ED25519 => Verifier::new_without_digest(&key)?.verify_oneshot(&signature, message),
+        Id::EC => {
+            let mut verifier = Verifier::new(ecdsa_digest(&key)?, &key)?;
+            verifier.update(message)?;
+            verifier.verify(&signature)
+        },
+        _ => return Err(PublicKeyError::Unsupported())
+    };
+    // openssl reports a malformed signature as an error, which is just a failed login here
+    Ok(verified.unwrap_or(false))
+}
+
+// One outstanding nonce per Hydra login challenge; taking it makes every signed message single use
+pub struct KeyChallenges {
+    ttl: Duration,
+    issued: Mutex<HashMap<String, (String, Instant)>>
+}
+
+impl KeyChallenges {
+    pub fn new(ttl: Duration) -> KeyChallenges {
+        KeyChallenges {
+            ttl,
+            issued: Mutex::new(HashMap::new())
+        }
+    }
+
+    // The message to sign names the Hydra challenge, so a signature cannot be replayed into another login
+    pub fn issue(&self, challenge: &str) -> String {
+        let mut nonce = [0u8; NONCE_BYTES];
+        OsRng.fill_bytes(&mut nonce);
+        let message = format!("travs-login:{}:{}", challenge, BASE64.encode(&nonce));
+        let mut issued = self.issued.lock().unwrap();
+        let ttl = self.ttl;
+        issued.retain(|_, (_, at)| at.elapsed() < ttl);
+        issued.insert(challenge.to_string(), (message.clone(), Instant::now()));
+        message
+    }
+
+    pub fn take(&self, challenge: &str) -> Option<String> {
+        match self.issued.lock().unwrap().remove(challenge) {
+            Some((message, at)) if at.elapsed() < self.ttl => Some(message),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use rand::rngs::OsRng;
+use rand::Rng;
+
+// Codes handed out per batch; generating a new batch replaces every unused code
+pub const RECOVERY_CODE_COUNT: usize = 10;
+// Characters per code, shown as two groups of five
+pub const RECOVERY_CODE_LENGTH: usize = 10;
+// Lowercase letters and digits without 0/o, 1/l/i, so codes survive being written down
+const ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+pub fn generate_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let raw: String = (0..RECOVERY_CODE_LENGTH)
+            .map(|_| ALPHABET[OsRng.gen_range(0, ALPHABET.len())] as char)
+            .collect();
+        format!("{}-{}", &raw[..RECOVERY_CODE_LENGTH / 2], &raw[RECOVERY_CODE_LENGTH / 2..])
+    }).collect()
+}
+
+// The form a code is hashed and compared in, whatever case or separators the user typed
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
@@ -126,6 +126,13 @@ impl Scope {
 
 pub struct ScopeStore {}
 
+// OAuth scopes map onto travs grants as namespace:scope
+pub const SCOPE_SEPARATOR: char = ':';
+
+// Protocol scopes every travs client may request on top of its System's namespace scopes.
+// email, phone and profile release the matching identity claims, see ClaimSource::required_scope.
+pub const PROTOCOL_SCOPES: [&str; 5] = ["openid", "offline", "email", "phone", "profile"];
+
 static TEMPLATE_ENGINE_SCOPE_STORE: OnceCell<handlebars::Handlebars> = OnceCell::new();
 
 impl ScopeStore {
@@ -189,7 +196,6 @@ impl ScopeStore {
             return Err(ScopeError::DoesNotExist().into())
         }
         let update: Scope = res.clone().ok_or(EntityError::Empty())?.add_entity(e.clone());
-        println!("DEBUG BITCH {:?}", update);
         db::save(serde_json::to_vec(&update)?)?;
 
         EntityStore::associate_scope(e.uid.as_ref().ok_or(EntityError::EmptyField("uid".to_string()))?, update, vec!["uid".to_string()])?;
@@ -197,6 +203,37 @@ impl ScopeStore {
         return Self::find_by_guid(guid, fields);
     }
 
+    // Scopes without a separator only pass when they are protocol scopes, anything else is dropped
+    pub fn filter_granted(requested: &[String], system_uid: &str, entity_guid: &str) -> Result<Vec<String>, failure::Error> {
+        let held: Vec<String> = EntityStore::find_by_guid(entity_guid, vec!["scope { uid }".to_string()])?
+            .and_then(|e| e.scopes)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|s| s.uid)
+            .collect();
+        let mut granted: Vec<String> = vec![];
+        for r in requested {
+            let (ns_name, scope_name) = match r.find(SCOPE_SEPARATOR) {
+                Some(i) => (&r[..i], &r[i + 1..]),
+                None => {
+                    if PROTOCOL_SCOPES.contains(&r.as_str()) {
+                        granted.push(r.clone());
+                    }
+                    continue
+                }
+            };
+            let ns_uid = match NamespaceStore::find_by_system_name(ns_name, system_uid, vec!["uid".to_string()])?.and_then(|ns| ns.uid) {
+                Some(uid) => uid,
+                None => continue
+            };
+            let scope = Self::find_by_namespace_type_name(scope_name, &ns_uid, vec!["uid".to_string()])?;
+            if scope.and_then(|s| s.uid).map_or(false, |uid| held.contains(&uid)) {
+                granted.push(r.clone());
+            }
+        }
+        Ok(granted)
+    }
+
     pub fn find_by_namespace_type_name(name: &str, namespace_uid: &str, fields: Vec<String>) -> Result<Option<Scope>, failure::Error> {
         let reg = TEMPLATE_ENGINE_SCOPE_STORE.get_or_init(|| {
             handlebars::Handlebars::new()
@@ -220,14 +257,11 @@ impl ScopeStore {
             "fields": fields
         });
         let query = reg.render_template(req, template_vars)?;
-        println!("QUERY {}", query);
         let mut vars: HashMap<String, String> = [
             ("$ns_uid".to_string(), namespace_uid.to_string()),
             ("$name".to_string(), name.to_string()),
         ].iter().cloned().collect();
-        println!("VAR {:?}", vars);
         let res = db::query(query, vars)?;
-        println!("RES {}", std::str::from_utf8(&res.json)?);
         let e: ScopeRoot = serde_json::from_slice(&res.json)?;
         match e.scope.len() {
             0 => Ok(None),
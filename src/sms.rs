@@ -0,0 +1,105 @@
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+use failure_derive::*;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::json;
+
+// A gateway that stops answering fails the send after these, like HydraClient, instead of holding the login open
+pub const SMS_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SMS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Fail)]
+pub enum SmsError {
+    #[fail(display = "Could not reach the SMS gateway: {}", _0)]
+    Transport(String),
+    #[fail(display = "SMS gateway refused the message with status {}", _0)]
+    Rejected(u16)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmsMessage {
+    pub to: String,
+    pub body: String
+}
+
+// Outgoing text messages for phone verification codes. Sending is async because the
+// HTTP gateway shares the actix runtime, unlike SMTP delivery which is blocking anyway.
+pub trait SmsGateway: Send + Sync {
+    fn send(&self, message: &SmsMessage) -> BoxFuture<'static, Result<(), SmsError>>;
+}
+
+// Posts {"to", "from", "body"} as JSON to a provider or an in-house relay
+pub struct HttpSmsGateway {
+    url: String,
+    from: String,
+    bearer_token: Option<String>,
+    client: reqwest::Client
+}
+
+impl HttpSmsGateway {
+    pub fn new(url: String, from: String) -> Result<HttpSmsGateway, SmsError> {
+        let client = reqwest::Client::builder()
+            .timeout(SMS_TIMEOUT)
+            .connect_timeout(SMS_CONNECT_TIMEOUT)
+            .build()
+            .map_err(|e| SmsError::Transport(e.to_string()))?;
+        Ok(HttpSmsGateway {
+            url,
+            from,
+            bearer_token: None,
+            client
+        })
+    }
+
+    pub fn bearer_token(mut self, token: String) -> Self {
+        self.bearer_token = Some(token);
+        self
+    }
+}
+
+impl SmsGateway for HttpSmsGateway {
+    fn send(&self, message: &SmsMessage) -> BoxFuture<'static, Result<(), SmsError>> {
+        let mut req = self.client.post(&self.url).json(&json!({
+            "to": message.to,
+            "from": self.from,
+            "body": message.body
+        }));
+        if let Some(token) = &self.bearer_token {
+            req = req.bearer_auth(token);
+        }
+        async move {
+            let resp = req.send().await.map_err(|e| SmsError::Transport(e.to_string()))?;
+            if !resp.status().is_success() {
+                return Err(SmsError::Rejected(resp.status().as_u16()))
+            }
+            Ok(())
+        }.boxed()
+    }
+}
+
+// Keeps every message so tests can read the codes travs sent; never selectable in a deployed config
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySmsGateway {
+    sent: Mutex<Vec<SmsMessage>>
+}
+
+#[cfg(test)]
+impl MemorySmsGateway {
+    pub fn new() -> MemorySmsGateway {
+        Default::default()
+    }
+
+    pub fn sent(&self) -> Vec<SmsMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl SmsGateway for MemorySmsGateway {
+    fn send(&self, message: &SmsMessage) -> BoxFuture<'static, Result<(), SmsError>> {
+        self.sent.lock().unwrap().push(message.clone());
+        futures::future::ok(()).boxed()
+    }
+}
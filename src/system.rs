@@ -10,6 +10,7 @@ use crate::identifier::{Identifier, IdentifierStore, IdentifierType, IdentifierE
 use crate::authenticator::{Authenticator, AuthenticatorStore};
 use crate::system::SystemError::ValidationFailed;
 use crate::namespace::Namespace;
+use crate::claims::ClaimMappings;
 
 #[derive(Debug, Fail)]
 pub enum SystemError {
@@ -19,6 +20,8 @@ pub enum SystemError {
     Empty(),
     #[fail(display = "System with guid does not exist")]
     DoesNotExist(),
+    #[fail(display = "OAuth client is already bound to a system")]
+    ClientAlreadyBound(),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -37,6 +40,13 @@ pub struct System {
     #[serde(rename = "namespace")]
     pub namespaces: Option<Vec<Namespace>>,
     pub name: Option<String>,
+    #[serde(rename = "client_id")]
+    pub client_ids: Option<Vec<String>>,
+    pub claim_mappings: Option<String>,
+    pub remember_for: Option<i32>,
+    pub require_mfa: Option<bool>,
+    // Logins and consents wait until the email or phone identifier has been confirmed
+    pub require_verified_identifier: Option<bool>,
     #[serde(rename = "dgraph.type")]
     pub dtype: Option<Vec<String>>
 }
@@ -95,6 +105,36 @@ impl System {
         self
     }
 
+    pub fn remember_for(mut self, remember_for: i32) -> Self {
+        self.remember_for = Some(remember_for);
+        self
+    }
+
+    pub fn require_mfa(mut self, require_mfa: bool) -> Self {
+        self.require_mfa = Some(require_mfa);
+        self
+    }
+
+    pub fn require_verified_identifier(mut self, require_verified_identifier: bool) -> Self {
+        self.require_verified_identifier = Some(require_verified_identifier);
+        self
+    }
+
+    pub fn add_client_id(mut self, client_id: String) -> Self {
+        if self.client_ids.is_none() {
+            self.client_ids = Some(vec![])
+        }
+        let mut curr_clients = self.client_ids.unwrap();
+        curr_clients.push(client_id);
+        self.client_ids = Some(curr_clients);
+        self
+    }
+
+    pub fn claim_mappings(mut self, mappings: &ClaimMappings) -> Result<Self, serde_json::Error> {
+        self.claim_mappings = Some(serde_json::to_string(mappings)?);
+        Ok(self)
+    }
+
     pub fn validate(&mut self) -> bool {
         if self.name.is_none() {
             return false
@@ -158,6 +198,74 @@ impl SystemStore {
         return Self::find_by_guid(guid, fields);
     }
 
+    pub fn associate_client_id(guid: &str, client_id: &str, fields: Vec<String>) -> Result<Option<System>, failure::Error> {
+        let bound = Self::find_by_client_id(client_id, vec!["uid".to_string(), "guid".to_string()])?;
+        if let Some(bound) = bound {
+            if bound.guid.as_deref() != Some(guid) {
+                return Err(SystemError::ClientAlreadyBound().into())
+            }
+            return Self::find_by_guid(guid, fields);
+        }
+        let res = Self::find_by_guid(guid, vec!["uid".to_string()])?;
+        if res.is_none() {
+            return Err(SystemError::DoesNotExist().into())
+        }
+        let update: System = res.clone().ok_or(SystemError::Empty())?.add_client_id(client_id.to_string());
+        db::save(serde_json::to_vec(&update)?)?;
+
+        return Self::find_by_guid(guid, fields);
+    }
+
+    pub fn dissociate_client_id(guid: &str, client_id: &str, fields: Vec<String>) -> Result<Option<System>, failure::Error> {
+        let res = Self::find_by_guid(guid, vec!["uid".to_string()])?;
+        let uid = res.and_then(|s| s.uid).ok_or(SystemError::DoesNotExist())?;
+        db::delete(serde_json::to_vec(&json!({
+            "uid": uid,
+            "client_id": client_id
+        }))?)?;
+
+        return Self::find_by_guid(guid, fields);
+    }
+
+    pub fn set_claim_mappings(guid: &str, mappings: &ClaimMappings, fields: Vec<String>) -> Result<Option<System>, failure::Error> {
+        let res = Self::find_by_guid(guid, vec!["uid".to_string()])?;
+        if res.is_none() {
+            return Err(SystemError::DoesNotExist().into())
+        }
+        let update: System = res.clone().ok_or(SystemError::Empty())?.claim_mappings(mappings)?;
+        db::save(serde_json::to_vec(&update)?)?;
+
+        return Self::find_by_guid(guid, fields);
+    }
+
+    pub fn find_by_client_id(client_id: &str, fields: Vec<String>) -> Result<Option<System>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_SYS_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query system($client_id: string) {
+			    system(func: eq(client_id, $client_id)) @filter(eq(dgraph.type, "System")) {
+			    {{#each fields }}
+				    {{this}}
+			    {{/each}}
+			}
+		}
+        "#;
+        let template_vars = &json!({
+            "fields": fields
+        });
+        let query = reg.render_template(req, template_vars)?;
+        let mut vars: HashMap<String, String> = [
+            ("$client_id".to_string(), client_id.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: SystemRoot = serde_json::from_slice(&res.json)?;
+        match e.system.len() {
+            0 => Ok(None),
+            _ => Ok(Some(e.system.get(0).ok_or(SystemError::Empty())?.clone()))
+        }
+    }
+
     pub fn find_by_guid(guid: &str, fields: Vec<String>) -> Result<Option<System>, failure::Error> {
         let reg = TEMPLATE_ENGINE_SYS_STORE.get_or_init(|| {
             handlebars::Handlebars::new()
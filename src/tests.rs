@@ -0,0 +1,337 @@
+use std::sync::Arc;
+use actix_http::cookie::Cookie;
+use actix_web::{http, test, web, App};
+use csrf::AesGcmCsrfProtection;
+use handlebars::Handlebars;
+use crate::{routes, AppData};
+use crate::csrf_form::issue_token;
+use crate::hydra::HydraClient;
+use crate::hydra_mock::MockHydra;
+use crate::login_policy::{LoginAttempts, LoginRejection, MAX_LOGIN_ATTEMPTS};
+use crate::mailer::MemoryMailer;
+use crate::mfa::{PendingLogins, PendingMfa, PENDING_MFA_COOKIE, PENDING_MFA_TTL};
+use crate::public_key::{KeyChallenges, KEY_CHALLENGE_TTL};
+use crate::sms::MemorySmsGateway;
+use crate::webauthn::{Ceremonies, CEREMONY_TTL};
+
+const CLIENT_ID: &str = "mock-client";
+
+// Handler state pointed at a running MockHydra; none of the flows exercised here reach Dgraph
+fn app_data(mock: &MockHydra) -> web::Data<AppData<'static>> {
+    app_data_with(mock, Arc::new(MemoryMailer::new()), Arc::new(MemorySmsGateway::new()))
+}
+
+// As app_data, with the mail and SMS doubles kept by the caller to read what was sent
+fn app_data_with(mock: &MockHydra, mailer: Arc<MemoryMailer>, sms: Arc<MemorySmsGateway>) -> web::Data<AppData<'static>> {
+    let (_server, base_url) = mock.start().unwrap();
+
+    let mut handlebars = Handlebars::new();
+    handlebars.register_templates_directory(".html", "./static").unwrap();
+
+    web::Data::new(AppData {
+        csrf_generator: web::Data::new(std::sync::Mutex::new(AesGcmCsrfProtection::from_key([7u8; 32]))),
+        hb: web::Data::new(handlebars),
+        hydra: web::Data::new(HydraClient::builder().base_url(base_url).build().unwrap()),
+        login_attempts: web::Data::new(LoginAttempts::new(MAX_LOGIN_ATTEMPTS)),
+        pending_mfa: web::Data::new(PendingLogins::new(PENDING_MFA_TTL)),
+        key_challenges: web::Data::new(KeyChallenges::new(KEY_CHALLENGE_TTL)),
+        webauthn_ceremonies: web::Data::new(Ceremonies::new(CEREMONY_TTL)),
+        admin_token: None,
+        trust_forwarded_for: false,
+        mailer: Some(mailer),
+        sms: Some(sms),
+        public_url: "http://travs.test".to_string()
+    })
+}
+
+fn csrf(data: &AppData<'_>) -> (String, Cookie<'static>) {
+    issue_token(data).unwrap()
+}
+
+fn location(resp: &actix_web::dev::ServiceResponse) -> String {
+    resp.headers().get(http::header::LOCATION).unwrap().to_str().unwrap().to_string()
+}
+
+#[actix_rt::test]
+async fn login_form_accepts_skipped_login() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("skip", CLIENT_ID, true, "subject-1"));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let req = test::TestRequest::get().uri("/login?challenge=skip").to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "http://hydra.mock/login/accept?challenge=skip");
+    let completions = mock.completions("skip");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].flow, "login");
+    assert_eq!(completions[0].action, "accept");
+    assert_eq!(completions[0].body["subject"], "subject-1");
+    assert_eq!(completions[0].body["remember"], false);
+}
+
+#[actix_rt::test]
+async fn login_form_fails_for_unknown_challenge() {
+    let mock = MockHydra::new();
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let req = test::TestRequest::get().uri("/login?challenge=missing").to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    assert!(mock.completions("missing").is_empty());
+}
+
+#[actix_rt::test]
+async fn login_cancel_rejects_login() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("cancel", CLIENT_ID, false, ""));
+    let sms = Arc::new(MemorySmsGateway::new());
+    let data = app_data_with(&mock, Arc::new(MemoryMailer::new()), sms.clone());
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .cookie(cookie)
+        .set_form(&[
+            ("identifier", "someone@example.com"),
+            ("authenticator", ""),
+            ("_csrf", token.as_str()),
+            ("challenge", "cancel"),
+            ("submit", "cancel")
+        ])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "http://hydra.mock/login/reject?challenge=cancel");
+    let completions = mock.completions("cancel");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].action, "reject");
+    let expected = LoginRejection::Cancelled.to_reject_request();
+    assert_eq!(completions[0].body["error"], expected.error);
+    assert_eq!(completions[0].body["status_code"], expected.status_code);
+    assert!(sms.sent().is_empty());
+}
+
+#[actix_rt::test]
+async fn login_without_csrf_token_is_refused() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("forged", CLIENT_ID, false, ""));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_form(&[
+            ("identifier", "someone@example.com"),
+            ("authenticator", ""),
+            ("_csrf", "forged"),
+            ("challenge", "forged"),
+            ("submit", "cancel")
+        ])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+    assert!(mock.completions("forged").is_empty());
+}
+
+#[actix_rt::test]
+async fn login_link_get_only_asks_for_confirmation() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("link", CLIENT_ID, false, ""));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let req = test::TestRequest::get().uri("/login/link?challenge=link&token=a%2Bb&remember=1").to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    let body = test::read_body(resp).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("action=\"/login/link/confirm\""));
+    assert!(body.contains("name=\"token\" value=\"a+b\""));
+    assert!(mock.completions("link").is_empty());
+}
+
+#[actix_rt::test]
+async fn login_link_cancel_rejects_login() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("link-cancel", CLIENT_ID, false, ""));
+    let mailer = Arc::new(MemoryMailer::new());
+    let data = app_data_with(&mock, mailer.clone(), Arc::new(MemorySmsGateway::new()));
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/login/link/confirm")
+        .cookie(cookie)
+        .set_form(&[
+            ("challenge", "link-cancel"),
+            ("token", "unused"),
+            ("_csrf", token.as_str()),
+            ("submit", "cancel")
+        ])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    let completions = mock.completions("link-cancel");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].action, "reject");
+    assert!(mailer.sent().is_empty());
+}
+
+// A verified login with nothing left to do, as first_factor_passed leaves it after the second factor
+fn pending_mfa(data: &AppData<'_>, challenge: &str) -> PendingMfa {
+    let pending = PendingMfa::new(
+        "subject-1".to_string(),
+        "0x1".to_string(),
+        "0x2".to_string(),
+        "someone@example.com".to_string(),
+        "system-1".to_string()
+    ).verified(true);
+    data.pending_mfa.insert(challenge, pending.clone());
+    pending
+}
+
+#[actix_rt::test]
+async fn pending_login_needs_the_browser_nonce() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("stolen", CLIENT_ID, false, ""));
+    let data = app_data(&mock);
+    pending_mfa(&data, "stolen");
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/login/recovery-codes")
+        .cookie(cookie)
+        .cookie(Cookie::new(PENDING_MFA_COOKIE, "guessed"))
+        .set_form(&[("challenge", "stolen"), ("_csrf", token.as_str()), ("submit", "continue")])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "/login?challenge=stolen");
+    assert!(mock.completions("stolen").is_empty());
+}
+
+#[actix_rt::test]
+async fn pending_login_continues_in_its_browser() {
+    let mock = MockHydra::new();
+    mock.add_login(MockHydra::login_fixture("owned", CLIENT_ID, false, ""));
+    let data = app_data(&mock);
+    let pending = pending_mfa(&data, "owned");
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/login/recovery-codes")
+        .cookie(cookie)
+        .cookie(Cookie::new(PENDING_MFA_COOKIE, pending.browser_nonce.clone()))
+        .set_form(&[("challenge", "owned"), ("_csrf", token.as_str()), ("submit", "continue")])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "http://hydra.mock/login/accept?challenge=owned");
+    let completions = mock.completions("owned");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].body["subject"], "subject-1");
+}
+
+#[actix_rt::test]
+async fn consent_deny_rejects_consent() {
+    let mock = MockHydra::new();
+    mock.add_consent(MockHydra::consent_fixture("deny", CLIENT_ID, false, "subject-1", vec!["openid".to_string()]));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/consent")
+        .cookie(cookie)
+        .set_form(&[
+            ("challenge", "deny"),
+            ("_csrf", token.as_str()),
+            ("grant_scope", "openid"),
+            ("submit", "reject")
+        ])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "http://hydra.mock/consent/reject?challenge=deny");
+    let completions = mock.completions("deny");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].flow, "consent");
+    assert_eq!(completions[0].action, "reject");
+    assert_eq!(completions[0].body["error"], "access_denied");
+}
+
+#[actix_rt::test]
+async fn logout_form_asks_before_accepting() {
+    let mock = MockHydra::new();
+    mock.add_logout("ask", MockHydra::logout_fixture("subject-1", true));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let req = test::TestRequest::get().uri("/logout?logout_challenge=ask").to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    assert!(mock.completions("ask").is_empty());
+}
+
+#[actix_rt::test]
+async fn logout_accept_clears_session_cookies() {
+    let mock = MockHydra::new();
+    mock.add_logout("accept", MockHydra::logout_fixture("subject-1", true));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/logout")
+        .cookie(cookie)
+        .set_form(&[("challenge", "accept"), ("_csrf", token.as_str()), ("submit", "accept")])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::FOUND);
+    assert_eq!(location(&resp), "http://hydra.mock/logout/accept?challenge=accept");
+    let removed: Vec<String> = resp.response().cookies().map(|c| c.name().to_string()).collect();
+    assert_eq!(removed, vec!["_csrf".to_string()]);
+    let completions = mock.completions("accept");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].flow, "logout");
+    assert_eq!(completions[0].action, "accept");
+}
+
+#[actix_rt::test]
+async fn logout_stay_rejects_logout() {
+    let mock = MockHydra::new();
+    mock.add_logout("stay", MockHydra::logout_fixture("subject-1", false));
+    let data = app_data(&mock);
+    let mut app = test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+    let (token, cookie) = csrf(&data);
+    let req = test::TestRequest::post()
+        .uri("/logout")
+        .cookie(cookie)
+        .set_form(&[("challenge", "stay"), ("_csrf", token.as_str()), ("submit", "reject")])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    let completions = mock.completions("stay");
+    assert_eq!(completions.len(), 1);
+    assert_eq!(completions[0].action, "reject");
+    assert_eq!(completions[0].body["status_code"], 401);
+}
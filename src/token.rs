@@ -0,0 +1,270 @@
+use serde_json::json;
+use std::fmt::{Formatter, Display};
+use once_cell::sync::OnceCell;
+use crate::db;
+use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use failure_derive::*;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+use openssl::hash::{hash, MessageDigest};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use crate::identifier::Identifier;
+
+const TOKEN_BYTES: usize = 32;
+// Short codes are typed in by hand, so they are only ever checked against one identifier's tokens
+const CODE_DIGITS: u32 = 6;
+
+#[derive(Debug, Fail)]
+pub enum TokenError {
+    #[fail(display = "Cannot extract token value from an empty array or None value")]
+    Empty(),
+    #[fail(display = "Could not hash token: {}", _0)]
+    Hash(String)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenRoot {
+    pub token: Vec<Token>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TokenPurpose {
+    password_reset,
+    email_verification,
+    phone_verification,
+    login_link,
+    phone_otp
+}
+
+impl Display for TokenPurpose {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// A single-use, expiring secret sent out of band. Only the SHA-256 of the token is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Token {
+    pub uid: Option<String>,
+    pub token_hash: Option<String>,
+    pub purpose: Option<TokenPurpose>,
+    pub expires_at: Option<i64>,
+    #[serde(rename = "identifier")]
+    pub identifiers: Option<Vec<Identifier>>,
+    #[serde(rename = "dgraph.type")]
+    pub dtype: Option<Vec<String>>
+}
+
+impl Token {
+    pub fn new() -> Token {
+        Token {
+            dtype: Some(vec!["Token".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    pub fn token_hash(mut self, token_hash: String) -> Self {
+        self.token_hash = Some(token_hash);
+        self
+    }
+
+    pub fn purpose(mut self, purpose: TokenPurpose) -> Self {
+        self.purpose = Some(purpose);
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: i64) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn add_identifier(mut self, identifier: Identifier) -> Self {
+        if self.identifiers.is_none() {
+            self.identifiers = Some(vec![])
+        }
+        let mut curr_ident = self.identifiers.unwrap();
+        curr_ident.push(identifier);
+        self.identifiers = Some(curr_ident);
+        self
+    }
+}
+
+pub struct TokenStore {}
+
+static TEMPLATE_ENGINE_TOKEN_STORE: OnceCell<handlebars::Handlebars> = OnceCell::new();
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl TokenStore {
+    fn _hash(raw: &str) -> Result<String, TokenError> {
+        hash(MessageDigest::sha256(), raw.as_bytes())
+            .map(|digest| HEXLOWER.encode(&digest))
+            .map_err(|e| TokenError::Hash(e.to_string()))
+    }
+
+    // A bound token only matches when presented together with the same binding
+    fn _bind(binding: &str, raw: &str) -> String {
+        format!("{}:{}", binding, raw)
+    }
+
+    fn _random() -> String {
+        let mut raw = [0u8; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut raw);
+        BASE64URL_NOPAD.encode(&raw)
+    }
+
+    fn _store(purpose: TokenPurpose, identifier_uid: &str, ttl_seconds: i64, hashed: &str) -> Result<(), failure::Error> {
+        let token = Token::new()
+            .token_hash(Self::_hash(hashed)?)
+            .purpose(purpose)
+            .expires_at(now() + ttl_seconds)
+            .add_identifier(Identifier::new().uid(identifier_uid.to_string()));
+        db::save(serde_json::to_vec(&token)?)?;
+        Ok(())
+    }
+
+    // Returns the raw token to send to the user; it cannot be recovered from Dgraph afterwards
+    pub fn issue(purpose: TokenPurpose, identifier_uid: &str, ttl_seconds: i64) -> Result<String, failure::Error> {
+        let raw = Self::_random();
+        Self::_store(purpose, identifier_uid, ttl_seconds, &raw)?;
+        Ok(raw)
+    }
+
+    // Like issue, but the token is only accepted back together with binding, e.g. the Hydra login challenge
+    pub fn issue_bound(purpose: TokenPurpose, identifier_uid: &str, ttl_seconds: i64, binding: &str) -> Result<String, failure::Error> {
+        let raw = Self::_random();
+        Self::_store(purpose, identifier_uid, ttl_seconds, &Self::_bind(binding, &raw))?;
+        Ok(raw)
+    }
+
+    // Issues a numeric code, replacing any earlier code of the same purpose for the identifier
+    pub fn issue_code(purpose: TokenPurpose, identifier_uid: &str, ttl_seconds: i64) -> Result<String, failure::Error> {
+        Self::revoke(&purpose, identifier_uid)?;
+        let modulus = 10u32.pow(CODE_DIGITS);
+        let raw = format!("{:0width$}", OsRng.next_u32() % modulus, width = CODE_DIGITS as usize);
+        Self::_store(purpose, identifier_uid, ttl_seconds, &raw)?;
+        Ok(raw)
+    }
+
+    fn _find_for_identifier(purpose: &TokenPurpose, identifier_uid: &str) -> Result<Vec<Token>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_TOKEN_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query token($purpose: string, $identifier: string) {
+			    token(func: eq(purpose, $purpose)) @filter(eq(dgraph.type, "Token")) @cascade {
+			        uid
+			        token_hash
+			        expires_at
+			        identifier @filter(uid($identifier)) {
+			            uid
+			        }
+			    }
+			}
+        "#;
+        let query = reg.render_template(req, &json!({}))?;
+        let vars: HashMap<String, String> = [
+            ("$purpose".to_string(), purpose.to_string()),
+            ("$identifier".to_string(), identifier_uid.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: TokenRoot = serde_json::from_slice(&res.json)?;
+        Ok(e.token)
+    }
+
+    pub fn revoke(purpose: &TokenPurpose, identifier_uid: &str) -> Result<(), failure::Error> {
+        for token in Self::_find_for_identifier(purpose, identifier_uid)? {
+            let uid = token.uid.clone().ok_or(TokenError::Empty())?;
+            db::delete(serde_json::to_vec(&json!({ "uid": uid }))?)?;
+        }
+        Ok(())
+    }
+
+    // Deletes the code if it matches one issued for the identifier and returns whether it was still valid
+    pub fn consume_code(purpose: &TokenPurpose, identifier_uid: &str, raw: &str) -> Result<bool, failure::Error> {
+        let hashed = Self::_hash(raw.trim())?;
+        let token = Self::_find_for_identifier(purpose, identifier_uid)?
+            .into_iter()
+            .find(|t| t.token_hash.as_ref() == Some(&hashed));
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(false)
+        };
+        let uid = token.uid.clone().ok_or(TokenError::Empty())?;
+        db::delete(serde_json::to_vec(&json!({ "uid": uid }))?)?;
+        Ok(token.expires_at.unwrap_or(0) > now())
+    }
+
+    // The lookup consume_code makes, for an identifier that does not exist. Dgraph leases uids upwards
+    // from 0x1, so the highest one is never an identifier's.
+    pub fn dummy_consume_code(purpose: &TokenPurpose, raw: &str) -> Result<(), failure::Error> {
+        let hashed = Self::_hash(raw.trim())?;
+        let _ = Self::_find_for_identifier(purpose, "0xffffffffffffffff")?
+            .into_iter()
+            .find(|t| t.token_hash.as_ref() == Some(&hashed));
+        Ok(())
+    }
+
+    fn _find(purpose: &TokenPurpose, raw: &str) -> Result<Option<Token>, failure::Error> {
+        let reg = TEMPLATE_ENGINE_TOKEN_STORE.get_or_init(|| {
+            handlebars::Handlebars::new()
+        });
+        let req: &'static str = r#"
+            query token($hash: string, $purpose: string) {
+			    token(func: eq(token_hash, $hash)) @filter(eq(purpose, $purpose) AND eq(dgraph.type, "Token")) {
+			        uid
+			        expires_at
+			        identifier {
+			            uid
+			        }
+			    }
+			}
+        "#;
+        let query = reg.render_template(req, &json!({}))?;
+        let vars: HashMap<String, String> = [
+            ("$hash".to_string(), Self::_hash(raw)?),
+            ("$purpose".to_string(), purpose.to_string())
+        ].iter().cloned().collect();
+        let res = db::query(query, vars)?;
+        let e: TokenRoot = serde_json::from_slice(&res.json)?;
+        match e.token.len() {
+            0 => Ok(None),
+            _ => Ok(Some(e.token.get(0).ok_or(TokenError::Empty())?.clone()))
+        }
+    }
+
+    fn _identifier_uid(token: &Token) -> Option<String> {
+        token.identifiers.as_ref().and_then(|i| i.get(0)).and_then(|i| i.uid.clone())
+    }
+
+    // Checks a token without using it up, for rendering the form it unlocks
+    pub fn peek(purpose: &TokenPurpose, raw: &str) -> Result<Option<String>, failure::Error> {
+        Ok(Self::_find(purpose, raw)?
+            .filter(|t| t.expires_at.unwrap_or(0) > now())
+            .and_then(|t| Self::_identifier_uid(&t)))
+    }
+
+    // Deletes the token and returns the identifier uid it was issued for, if it was still valid
+    pub fn consume(purpose: &TokenPurpose, raw: &str) -> Result<Option<String>, failure::Error> {
+        let token = match Self::_find(purpose, raw)? {
+            Some(token) => token,
+            None => return Ok(None)
+        };
+        let uid = token.uid.clone().ok_or(TokenError::Empty())?;
+        db::delete(serde_json::to_vec(&json!({ "uid": uid }))?)?;
+        if token.expires_at.unwrap_or(0) <= now() {
+            return Ok(None)
+        }
+        Ok(Self::_identifier_uid(&token))
+    }
+
+    pub fn consume_bound(purpose: &TokenPurpose, binding: &str, raw: &str) -> Result<Option<String>, failure::Error> {
+        Self::consume(purpose, &Self::_bind(binding, raw))
+    }
+}
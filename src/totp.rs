@@ -0,0 +1,140 @@
+use once_cell::sync::OnceCell;
+use failure_derive::*;
+use data_encoding::{BASE32_NOPAD, BASE64};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_PERIOD_SECONDS: u64 = 30;
+// Codes from one period either side of now are accepted to absorb clock drift
+pub const TOTP_SKEW_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+
+#[derive(Debug, Fail)]
+pub enum TotpError {
+    #[fail(display = "TOTP secrets cannot be used because no totp.key is configured")]
+    NotConfigured(),
+    #[fail(display = "Stored TOTP secret could not be decrypted")]
+    Corrupt(),
+    #[fail(display = "TOTP crypto operation failed: {}", _0)]
+    Crypto(String)
+}
+
+impl From<openssl::error::ErrorStack> for TotpError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        TotpError::Crypto(e.to_string())
+    }
+}
+
+struct TotpSettings {
+    key: [u8; 32],
+    issuer: String
+}
+
+static SETTINGS: OnceCell<TotpSettings> = OnceCell::new();
+
+pub fn configure(key: [u8; 32], issuer: String) {
+    let _ = SETTINGS.set(TotpSettings { key, issuer });
+}
+
+fn settings() -> Result<&'static TotpSettings, TotpError> {
+    SETTINGS.get().ok_or(TotpError::NotConfigured())
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+// Stored as base64(nonce || tag || ciphertext) so Authenticator.value never holds the raw secret
+pub fn encrypt_secret(secret: &[u8]) -> Result<String, TotpError> {
+    let settings = settings()?;
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let mut tag = [0u8; TAG_BYTES];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), &settings.key, Some(&nonce[..]), b"totp", secret, &mut tag)?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&tag);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(BASE64.encode(&sealed))
+}
+
+pub fn decrypt_secret(sealed: &str) -> Result<Vec<u8>, TotpError> {
+    let settings = settings()?;
+    let sealed = BASE64.decode(sealed.as_bytes()).map_err(|_| TotpError::Corrupt())?;
+    if sealed.len() < NONCE_BYTES + TAG_BYTES {
+        return Err(TotpError::Corrupt())
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_BYTES);
+    let (tag, ciphertext) = rest.split_at(TAG_BYTES);
+    decrypt_aead(Cipher::aes_256_gcm(), &settings.key, Some(nonce), b"totp", ciphertext, tag)
+        .map_err(|_| TotpError::Corrupt())
+}
+
+// RFC 4226 HOTP over HMAC-SHA1, which RFC 6238 runs against the time step counter
+fn hotp(secret: &[u8], counter: u64) -> Result<u32, TotpError> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&counter.to_be_bytes())?;
+    let mac = signer.sign_to_vec()?;
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = ((mac[offset] as u32 & 0x7f) << 24)
+        | ((mac[offset + 1] as u32) << 16)
+        | ((mac[offset + 2] as u32) << 8)
+        | (mac[offset + 3] as u32);
+    Ok(binary % 10u32.pow(TOTP_DIGITS))
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The time step the code belongs to, so callers can refuse a code whose step was already used
+pub fn matched_step(secret: &[u8], code: &str, at: u64) -> Result<Option<u64>, TotpError> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None)
+    }
+    let expected: u32 = code.parse().map_err(|_| TotpError::Corrupt())?;
+    let step = at / TOTP_PERIOD_SECONDS;
+    let mut matched = None;
+    for counter in step.saturating_sub(TOTP_SKEW_STEPS)..=step + TOTP_SKEW_STEPS {
+        // Every candidate is computed so timing does not depend on which step matched
+        if hotp(secret, counter)? == expected {
+            matched = Some(counter);
+        }
+    }
+    Ok(matched)
+}
+
+fn encode_label(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>().replace('+', "%20")
+}
+
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> Result<String, TotpError> {
+    let issuer = encode_label(&settings()?.issuer);
+    Ok(format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        encode_label(account),
+        BASE32_NOPAD.encode(secret),
+        issuer,
+        TOTP_DIGITS,
+        TOTP_PERIOD_SECONDS
+    ))
+}
+
+pub fn qr_svg(uri: &str) -> Result<String, TotpError> {
+    let code = qrcode::QrCode::new(uri.as_bytes()).map_err(|e| TotpError::Crypto(e.to_string()))?;
+    Ok(code.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build())
+}
@@ -0,0 +1,203 @@
+use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::form_urlencoded;
+use crate::AppData;
+use crate::csrf_form::{CsrfForm, CsrfProtected, issue_token};
+use crate::entity::EntityStore;
+use crate::identifier::{Identifier, IdentifierStore, IdentifierType};
+use crate::lockout;
+use crate::login_policy::LoginRejection;
+use crate::mailer::MailMessage;
+use crate::mfa::PendingMfa;
+use crate::sms::SmsMessage;
+use crate::token::{TokenPurpose, TokenStore};
+
+pub const EMAIL_LINK_TTL_SECONDS: i64 = 86400;
+pub const PHONE_CODE_TTL_SECONDS: i64 = 600;
+// Another code or link is only sent once this long has passed since the last one
+pub const RESEND_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VerifyReq {
+    code: Option<String>,
+    challenge: String,
+    _csrf: String,
+    submit: Option<String>
+}
+
+impl CsrfProtected for VerifyReq {
+    fn csrf_token(&self) -> Option<String> {
+        Some(self._csrf.clone())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LinkQuery {
+    token: String,
+    challenge: Option<String>
+}
+
+fn find_identifier(uid: &str) -> Result<Identifier, Error> {
+    IdentifierStore::find_by_uid(uid, vec![
+        "uid".to_string(),
+        "identifier_type".to_string(),
+        "value".to_string(),
+        "verified".to_string(),
+        "verification_sent_at".to_string()
+    ])
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or(error::ErrorInternalServerError("Identifier does not exist"))
+}
+
+// Emails a confirmation link or texts a code, unless one went out within RESEND_INTERVAL_SECONDS
+pub async fn send(data: &AppData<'_>, identifier: &Identifier, challenge: &str) -> Result<(), Error> {
+    let uid = identifier.uid.clone().ok_or(error::ErrorInternalServerError("Identifier is missing a uid"))?;
+    let value = identifier.value.clone().ok_or(error::ErrorInternalServerError("Identifier is missing a value"))?;
+    if identifier.verification_sent_at.unwrap_or(0) + RESEND_INTERVAL_SECONDS > lockout::now() {
+        return Ok(())
+    }
+
+    match identifier.identifier_type {
+        Some(IdentifierType::email) => {
+            let mailer = data.mailer.clone().ok_or(error::ErrorInternalServerError("No mailer is configured to verify email addresses"))?;
+            TokenStore::revoke(&TokenPurpose::email_verification, &uid).map_err(error::ErrorInternalServerError)?;
+            let token = TokenStore::issue(TokenPurpose::email_verification, &uid, EMAIL_LINK_TTL_SECONDS)
+                .map_err(error::ErrorInternalServerError)?;
+            let query = form_urlencoded::Serializer::new(String::new())
+                .append_pair("token", &token)
+                .append_pair("challenge", challenge)
+                .finish();
+            let link = format!("{}/verify/identifier?{}", data.public_url, query);
+            let message = MailMessage {
+                to: value.clone(),
+                subject: "Confirm your email address".to_string(),
+                body: format!("Follow this link within a day to confirm that {} belongs to you:\n{}\n\nIf you did not try to sign in, ignore this message.", value, link)
+            };
+            // SMTP blocks, so the mail goes out from the blocking pool after the response
+            actix_rt::spawn(async move {
+                if let Err(e) = web::block(move || mailer.send(&message)).await {
+                    eprintln!("Could not send an email confirmation link: {}", e);
+                }
+            });
+        },
+        Some(IdentifierType::phone) => {
+            let gateway = data.sms.as_ref().ok_or(error::ErrorInternalServerError("No SMS gateway is configured to verify phone numbers"))?;
+            let code = TokenStore::issue_code(TokenPurpose::phone_verification, &uid, PHONE_CODE_TTL_SECONDS)
+                .map_err(error::ErrorInternalServerError)?;
+            gateway.send(&SmsMessage {
+                to: value,
+                body: format!("Your travs verification code is {}. It expires in 10 minutes.", code)
+            }).await.map_err(error::ErrorInternalServerError)?;
+        },
+        _ => return Err(error::ErrorInternalServerError("Only email and phone identifiers can be verified"))
+    }
+
+    IdentifierStore::mark_verification_sent(&uid).map_err(error::ErrorInternalServerError)
+}
+
+// Renders the prompt for the code, or the notice to follow the emailed link
+pub fn step(data: &AppData<'_>, challenge: &str, pending: &PendingMfa, failed: bool) -> Result<HttpResponse, Error> {
+    let identifier = find_identifier(&pending.identifier_uid)?;
+    let (token, csrf_cookie) = issue_token(data)?;
+
+    let tmpl_data = json!({
+        "challenge": challenge,
+        "csrf_token": token,
+        "identifier": pending.identifier.clone(),
+        "phone": identifier.identifier_type == Some(IdentifierType::phone),
+        "failed": failed
+    });
+
+    let body = data.hb.render("verify_identifier", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+pub async fn start(data: &AppData<'_>, challenge: &str, pending: &PendingMfa) -> Result<HttpResponse, Error> {
+    send(data, &find_identifier(&pending.identifier_uid)?, challenge).await?;
+    step(data, challenge, pending, false)
+}
+
+pub async fn login_verify(item: CsrfForm<VerifyReq>, req: HttpRequest, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let challenge = item.challenge.clone();
+
+    if item.submit.as_deref() == Some("cancel") {
+        return crate::reject_login(&data, &challenge, LoginRejection::Cancelled).await
+    }
+
+    // Only a login whose required factors are done can get this far
+    let pending = match crate::pending_login(&data, &challenge, &req) {
+        Some(pending) if pending.verified && pending.verify_identifier => pending,
+        _ => return Ok(HttpResponse::Found().header(actix_web::http::header::LOCATION, format!("/login?challenge={}", challenge.clone())).finish())
+    };
+    let identifier = find_identifier(&pending.identifier_uid)?;
+
+    if item.submit.as_deref() == Some("resend") {
+        send(&data, &identifier, &challenge).await?;
+        return step(&data, &challenge, &pending, false)
+    }
+
+    // Phones confirm with the texted code; emails are confirmed once the link has been opened anywhere
+    let confirmed = match identifier.identifier_type {
+        Some(IdentifierType::phone) => {
+            let code = item.code.clone().unwrap_or_default();
+            let valid = TokenStore::consume_code(&TokenPurpose::phone_verification, &pending.identifier_uid, &code)
+                .map_err(error::ErrorInternalServerError)?;
+            if valid {
+                IdentifierStore::mark_verified(&pending.identifier_uid).map_err(error::ErrorInternalServerError)?;
+            }
+            valid
+        },
+        _ => identifier.verified.unwrap_or(false)
+    };
+
+    if !confirmed {
+        if let Some(rejection) = data.login_attempts.record_failure(&challenge) {
+            return crate::reject_login(&data, &challenge, rejection).await
+        }
+        return step(&data, &challenge, &pending, true)
+    }
+
+    data.login_attempts.clear(&challenge);
+
+    crate::continue_login(&data, &challenge, pending.verify_identifier(false)).await
+}
+
+// Target of the emailed link. It only confirms the address; the login itself continues in the window that started it.
+pub async fn confirm_link(query: web::Query<LinkQuery>, data: web::Data<AppData<'_>>) -> Result<HttpResponse, Error> {
+    let identifier_uid = match TokenStore::consume(&TokenPurpose::email_verification, &query.token).map_err(error::ErrorInternalServerError)? {
+        Some(identifier_uid) => identifier_uid,
+        None => {
+            let body = data.hb.render("error", &json!({ "message": "This confirmation link is invalid or has expired. Sign in again to get a new one." }))
+                .map_err(error::ErrorInternalServerError)?;
+            return Ok(HttpResponse::BadRequest().content_type("text/html").body(body))
+        }
+    };
+
+    IdentifierStore::mark_verified(&identifier_uid).map_err(error::ErrorInternalServerError)?;
+
+    let (token, csrf_cookie) = issue_token(&data)?;
+
+    let tmpl_data = json!({
+        "csrf_token": token,
+        "challenge": query.challenge.clone().filter(|c| !c.is_empty())
+    });
+
+    let body = data.hb.render("identifier_verified", &tmpl_data).map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().header(actix_web::http::header::SET_COOKIE, csrf_cookie.to_string()).body(body))
+}
+
+// An entity without email or phone identifiers has nothing to verify; otherwise one of them must be confirmed
+pub fn subject_verified(subject: &str) -> Result<bool, failure::Error> {
+    let entity = match EntityStore::find_by_guid(subject, vec!["identifier { identifier_type verified }".to_string()])? {
+        Some(entity) => entity,
+        None => return Ok(false)
+    };
+    let verifiable: Vec<Identifier> = entity.identifiers.unwrap_or_default()
+        .into_iter()
+        .filter(|i| i.identifier_type.as_ref().map(|t| t.verifiable()).unwrap_or(false))
+        .collect();
+    Ok(verifiable.is_empty() || verifiable.iter().any(|i| i.verified.unwrap_or(false)))
+}
@@ -0,0 +1,575 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::OnceCell;
+use failure_derive::*;
+use serde_derive::{Deserialize, Serialize};
+use serde_cbor::Value;
+use serde_json::json;
+use data_encoding::BASE64URL_NOPAD;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+// How long a browser has to finish navigator.credentials.create/get for an issued challenge
+pub const CEREMONY_TTL: Duration = Duration::from_secs(300);
+const CHALLENGE_BYTES: usize = 32;
+const CEREMONY_TIMEOUT_MS: u64 = 60000;
+
+// COSE algorithm identifiers travs can verify
+const COSE_ES256: i128 = -7;
+const COSE_EDDSA: i128 = -8;
+const COSE_RS256: i128 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Debug, Fail)]
+pub enum WebauthnError {
+    #[fail(display = "WebAuthn cannot be used because no relying party is configured")]
+    NotConfigured(),
+    #[fail(display = "WebAuthn response is malformed: {}", _0)]
+    Malformed(String),
+    #[fail(display = "WebAuthn response does not answer the issued challenge")]
+    ChallengeMismatch(),
+    #[fail(display = "WebAuthn response came from an unexpected origin")]
+    OriginMismatch(),
+    #[fail(display = "WebAuthn response is scoped to a different relying party")]
+    RpIdMismatch(),
+    #[fail(display = "The authenticator did not confirm user presence")]
+    UserNotPresent(),
+    #[fail(display = "Credential public key uses an unsupported COSE algorithm")]
+    Unsupported(),
+    #[fail(display = "Signature counter did not increase, the credential may be cloned")]
+    CounterRegression(),
+    #[fail(display = "WebAuthn crypto operation failed: {}", _0)]
+    Crypto(String)
+}
+
+impl From<openssl::error::ErrorStack> for WebauthnError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        WebauthnError::Crypto(e.to_string())
+    }
+}
+
+struct RelyingParty {
+    id: String,
+    name: String,
+    origin: String
+}
+
+static RELYING_PARTY: OnceCell<RelyingParty> = OnceCell::new();
+
+pub fn configure(id: String, name: String, origin: String) {
+    let _ = RELYING_PARTY.set(RelyingParty { id, name, origin });
+}
+
+fn relying_party() -> Result<&'static RelyingParty, WebauthnError> {
+    RELYING_PARTY.get().ok_or(WebauthnError::NotConfigured())
+}
+
+// Serialized as JSON into Authenticator.value for the webauthn authenticator type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredCredential {
+    // base64url credential id, as the browser reports it in PublicKeyCredential.id
+    pub credential_id: String,
+    // base64url COSE_Key from the attested credential data
+    pub public_key: String,
+    pub counter: u32
+}
+
+impl StoredCredential {
+    pub fn from_value(value: &str) -> Result<StoredCredential, WebauthnError> {
+        serde_json::from_str(value).map_err(|e| WebauthnError::Malformed(e.to_string()))
+    }
+
+    pub fn to_value(&self) -> Result<String, WebauthnError> {
+        serde_json::to_string(self).map_err(|e| WebauthnError::Malformed(e.to_string()))
+    }
+}
+
+// Fields of PublicKeyCredential the login pages post back, binary members base64url encoded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistrationResponse {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssertionResponse {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String
+}
+
+#[derive(Deserialize, Debug)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String
+}
+
+struct AuthenticatorData {
+    counter: u32,
+    credential: Option<(Vec<u8>, Vec<u8>)>
+}
+
+pub fn generate_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_BYTES];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>, WebauthnError> {
+    BASE64URL_NOPAD.decode(value.trim_end_matches('=').as_bytes())
+        .map_err(|_| WebauthnError::Malformed(format!("{} is not base64url", field)))
+}
+
+// PublicKeyCredentialCreationOptions with binary members base64url encoded for the page script to decode
+pub fn creation_options(challenge: &[u8], user_id: &str, user_name: &str, exclude: &[StoredCredential]) -> Result<serde_json::Value, WebauthnError> {
+    let rp = relying_party()?;
+    Ok(json!({
+        "challenge": BASE64URL_NOPAD.encode(challenge),
+        "rp": { "id": rp.id.clone(), "name": rp.name.clone() },
+        "user": {
+            "id": BASE64URL_NOPAD.encode(user_id.as_bytes()),
+            "name": user_name,
+            "displayName": user_name
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": COSE_ES256 as i64 },
+            { "type": "public-key", "alg": COSE_EDDSA as i64 },
+            { "type": "public-key", "alg": COSE_RS256 as i64 }
+        ],
+        "excludeCredentials": exclude.iter().map(|c| json!({ "type": "public-key", "id": c.credential_id.clone() })).collect::<Vec<serde_json::Value>>(),
+        "authenticatorSelection": { "residentKey": "preferred", "userVerification": "preferred" },
+        "attestation": "none",
+        "timeout": CEREMONY_TIMEOUT_MS
+    }))
+}
+
+pub fn request_options(challenge: &[u8], allow: &[StoredCredential]) -> Result<serde_json::Value, WebauthnError> {
+    let rp = relying_party()?;
+    Ok(json!({
+        "challenge": BASE64URL_NOPAD.encode(challenge),
+        "rpId": rp.id.clone(),
+        "allowCredentials": allow.iter().map(|c| json!({ "type": "public-key", "id": c.credential_id.clone() })).collect::<Vec<serde_json::Value>>(),
+        "userVerification": "preferred",
+        "timeout": CEREMONY_TIMEOUT_MS
+    }))
+}
+
+fn verify_client_data(raw: &[u8], ceremony: &str, challenge: &[u8]) -> Result<(), WebauthnError> {
+    let rp = relying_party()?;
+    let client_data: ClientData = serde_json::from_slice(raw).map_err(|e| WebauthnError::Malformed(e.to_string()))?;
+    if client_data.ceremony != ceremony {
+        return Err(WebauthnError::Malformed(format!("expected a {} response", ceremony)))
+    }
+    if decode("challenge", &client_data.challenge)? != challenge {
+        return Err(WebauthnError::ChallengeMismatch())
+    }
+    if client_data.origin != rp.origin {
+        return Err(WebauthnError::OriginMismatch())
+    }
+    Ok(())
+}
+
+fn parse_authenticator_data(raw: &[u8]) -> Result<AuthenticatorData, WebauthnError> {
+    let rp = relying_party()?;
+    if raw.len() < 37 {
+        return Err(WebauthnError::Malformed("authenticator data is too short".to_string()))
+    }
+    if raw[..32] != hash(MessageDigest::sha256(), rp.id.as_bytes())?[..] {
+        return Err(WebauthnError::RpIdMismatch())
+    }
+    let flags = raw[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebauthnError::UserNotPresent())
+    }
+    let counter = u32::from_be_bytes([raw[33], raw[34], raw[35], raw[36]]);
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, then one CBOR COSE_Key
+        let rest = &raw[37..];
+        if rest.len() < 18 {
+            return Err(WebauthnError::Malformed("attested credential data is too short".to_string()))
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        if rest.len() < 18 + id_len {
+            return Err(WebauthnError::Malformed("credential id is truncated".to_string()))
+        }
+        let credential_id = rest[18..18 + id_len].to_vec();
+        let key_bytes = &rest[18 + id_len..];
+        let mut de = serde_cbor::Deserializer::from_slice(key_bytes);
+        <Value as serde::Deserialize>::deserialize(&mut de).map_err(|e| WebauthnError::Malformed(e.to_string()))?;
+        Some((credential_id, key_bytes[..de.byte_offset()].to_vec()))
+    } else {
+        None
+    };
+    Ok(AuthenticatorData { counter, credential })
+}
+
+fn cose_bytes(key: &BTreeMap<Value, Value>, label: i128) -> Result<Vec<u8>, WebauthnError> {
+    match key.get(&Value::Integer(label)) {
+        Some(Value::Bytes(b)) => Ok(b.clone()),
+        _ => Err(WebauthnError::Malformed(format!("COSE key is missing parameter {}", label)))
+    }
+}
+
+// Turns a COSE_Key into an openssl public key plus the digest its signatures are made over
+fn cose_public_key(raw: &[u8]) -> Result<(PKey<Public>, Option<MessageDigest>), WebauthnError> {
+    let key: BTreeMap<Value, Value> = serde_cbor::from_slice(raw).map_err(|e| WebauthnError::Malformed(e.to_string()))?;
+    let alg = match key.get(&Value::Integer(3)) {
+        Some(Value::Integer(alg)) => *alg,
+        _ => return Err(WebauthnError::Unsupported())
+    };
+    match alg {
+        COSE_ES256 => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            let x = BigNum::from_slice(&cose_bytes(&key, -2)?)?;
+            let y = BigNum::from_slice(&cose_bytes(&key, -3)?)?;
+            let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+            ec.check_key()?;
+            Ok((PKey::from_ec_key(ec)?, Some(MessageDigest::sha256())))
+        },
+        COSE_EDDSA => Ok((PKey::public_key_from_raw_bytes(&cose_bytes(&key, -2)?, Id::ED25519)?, None)),
+        COSE_RS256 => {
+            let rsa = Rsa::from_public_components(BigNum::from_slice(&cose_bytes(&key, -1)?)?, BigNum::from_slice(&cose_bytes(&key, -2)?)?)?;
+            Ok((PKey::from_rsa(rsa)?, Some(MessageDigest::sha256())))
+        },
+        _ => Err(WebauthnError::Unsupported())
+    }
+}
+
+// Registration ceremony: checks the response answers challenge and returns the credential to store.
+// travs asks for attestation "none", so the attestation statement itself is not evaluated.
+pub fn finish_registration(challenge: &[u8], response: &RegistrationResponse) -> Result<StoredCredential, WebauthnError> {
+    verify_client_data(&decode("clientDataJSON", &response.client_data_json)?, "webauthn.create", challenge)?;
+
+    let attestation: BTreeMap<String, Value> = serde_cbor::from_slice(&decode("attestationObject", &response.attestation_object)?)
+        .map_err(|e| WebauthnError::Malformed(e.to_string()))?;
+    let auth_data = match attestation.get("authData") {
+        Some(Value::Bytes(b)) => parse_authenticator_data(b)?,
+        _ => return Err(WebauthnError::Malformed("attestationObject has no authData".to_string()))
+    };
+    let (credential_id, public_key) = auth_data.credential
+        .ok_or(WebauthnError::Malformed("registration carries no attested credential".to_string()))?;
+
+    // Rejects keys travs could never verify an assertion from
+    cose_public_key(&public_key)?;
+
+    Ok(StoredCredential {
+        credential_id: BASE64URL_NOPAD.encode(&credential_id),
+        public_key: BASE64URL_NOPAD.encode(&public_key),
+        counter: auth_data.counter
+    })
+}
+
+// Assertion ceremony: verifies the signature over authenticatorData || SHA-256(clientDataJSON)
+// and returns the credential with its updated signature counter
+pub fn finish_assertion(challenge: &[u8], stored: &StoredCredential, response: &AssertionResponse) -> Result<StoredCredential, WebauthnError> {
+    let client_data = decode("clientDataJSON", &response.client_data_json)?;
+    verify_client_data(&client_data, "webauthn.get", challenge)?;
+
+    let raw_auth_data = decode("authenticatorData", &response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&raw_auth_data)?;
+
+    let (key, digest) = cose_public_key(&decode("public_key", &stored.public_key)?)?;
+    let mut signed = raw_auth_data.clone();
+    signed.extend_from_slice(&hash(MessageDigest::sha256(), &client_data)?);
+    let signature = decode("signature", &response.signature)?;
+    let verified = match digest {
+        Some(digest) => {
+            let mut verifier = Verifier::new(digest, &key)?;
+            verifier.update(&signed)?;
+            verifier.verify(&signature).unwrap_or(false)
+        },
+        None => Verifier::new_without_digest(&key)?.verify_oneshot(&signature, &signed).unwrap_or(false)
+    };
+    if !verified {
+        return Err(WebauthnError::Crypto("assertion signature does not verify".to_string()))
+    }
+
+    // Authenticators that do not count report zero every time, anything else must move forward
+    if (auth_data.counter != 0 || stored.counter != 0) && auth_data.counter <= stored.counter {
+        return Err(WebauthnError::CounterRegression())
+    }
+
+    Ok(StoredCredential {
+        counter: auth_data.counter,
+        ..stored.clone()
+    })
+}
+
+// Outstanding ceremony challenges per Hydra login challenge, each usable once
+pub struct Ceremonies {
+    ttl: Duration,
+    issued: Mutex<HashMap<String, (Vec<u8>, Instant)>>
+}
+
+impl Ceremonies {
+    pub fn new(ttl: Duration) -> Ceremonies {
+        Ceremonies {
+            ttl,
+            issued: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn issue(&self, challenge: &str) -> Vec<u8> {
+        let ceremony = generate_challenge();
+        let mut issued = self.issued.lock().unwrap();
+        let ttl = self.ttl;
+        issued.retain(|_, (_, at)| at.elapsed() < ttl);
+        issued.insert(challenge.to_string(), (ceremony.clone(), Instant::now()));
+        ceremony
+    }
+
+    pub fn take(&self, challenge: &str) -> Option<Vec<u8>> {
+        match self.issued.lock().unwrap().remove(challenge) {
+            Some((ceremony, at)) if at.elapsed() < self.ttl => Some(ceremony),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNumContext;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    const RP_ID: &str = "travs.test";
+    const ORIGIN: &str = "https://travs.test";
+
+    struct TestKey {
+        key: PKey<Private>,
+        cose: Vec<u8>,
+        digest: Option<MessageDigest>
+    }
+
+    impl TestKey {
+        fn p256() -> TestKey {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            let ec = EcKey::generate(&group).unwrap();
+            let mut x = BigNum::new().unwrap();
+            let mut y = BigNum::new().unwrap();
+            ec.public_key().affine_coordinates_gfp(&group, &mut x, &mut y, &mut BigNumContext::new().unwrap()).unwrap();
+            let cose = cose_key(vec![
+                (1, Value::Integer(2)),
+                (3, Value::Integer(COSE_ES256)),
+                (-1, Value::Integer(1)),
+                (-2, Value::Bytes(x.to_vec_padded(32).unwrap())),
+                (-3, Value::Bytes(y.to_vec_padded(32).unwrap()))
+            ]);
+            TestKey { key: PKey::from_ec_key(ec).unwrap(), cose, digest: Some(MessageDigest::sha256()) }
+        }
+
+        fn ed25519() -> TestKey {
+            let key = PKey::generate_ed25519().unwrap();
+            let cose = cose_key(vec![
+                (1, Value::Integer(1)),
+                (3, Value::Integer(COSE_EDDSA)),
+                (-1, Value::Integer(6)),
+                (-2, Value::Bytes(key.raw_public_key().unwrap()))
+            ]);
+            TestKey { key, cose, digest: None }
+        }
+
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            match self.digest {
+                Some(digest) => {
+                    let mut signer = Signer::new(digest, &self.key).unwrap();
+                    signer.update(data).unwrap();
+                    signer.sign_to_vec().unwrap()
+                },
+                None => Signer::new_without_digest(&self.key).unwrap().sign_oneshot_to_vec(data).unwrap()
+            }
+        }
+    }
+
+    fn setup() {
+        configure(RP_ID.to_string(), "travs".to_string(), ORIGIN.to_string());
+    }
+
+    fn cose_key(params: Vec<(i128, Value)>) -> Vec<u8> {
+        let key: BTreeMap<Value, Value> = params.into_iter().map(|(k, v)| (Value::Integer(k), v)).collect();
+        serde_cbor::to_vec(&key).unwrap()
+    }
+
+    fn client_data(ceremony: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "type": ceremony,
+            "challenge": BASE64URL_NOPAD.encode(challenge),
+            "origin": origin
+        })).unwrap()
+    }
+
+    fn authenticator_data(rp_id: &str, flags: u8, counter: u32, credential: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = hash(MessageDigest::sha256(), rp_id.as_bytes()).unwrap().to_vec();
+        data.push(flags);
+        data.extend_from_slice(&counter.to_be_bytes());
+        if let Some((id, cose)) = credential {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            data.extend_from_slice(id);
+            data.extend_from_slice(cose);
+        }
+        data
+    }
+
+    fn registration(key: &TestKey, challenge: &[u8], origin: &str, rp_id: &str, flags: u8) -> RegistrationResponse {
+        let credential_id = b"test-credential";
+        let mut attestation = BTreeMap::new();
+        attestation.insert("fmt".to_string(), Value::Text("none".to_string()));
+        attestation.insert("attStmt".to_string(), Value::Map(BTreeMap::new()));
+        attestation.insert("authData".to_string(), Value::Bytes(authenticator_data(rp_id, flags | FLAG_ATTESTED_CREDENTIAL, 0, Some((credential_id, &key.cose)))));
+        RegistrationResponse {
+            id: BASE64URL_NOPAD.encode(credential_id),
+            client_data_json: BASE64URL_NOPAD.encode(&client_data("webauthn.create", challenge, origin)),
+            attestation_object: BASE64URL_NOPAD.encode(&serde_cbor::to_vec(&attestation).unwrap())
+        }
+    }
+
+    fn assertion(key: &TestKey, stored: &StoredCredential, challenge: &[u8], origin: &str, rp_id: &str, flags: u8, counter: u32) -> AssertionResponse {
+        let client_data = client_data("webauthn.get", challenge, origin);
+        let auth_data = authenticator_data(rp_id, flags, counter, None);
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&hash(MessageDigest::sha256(), &client_data).unwrap());
+        AssertionResponse {
+            id: stored.credential_id.clone(),
+            client_data_json: BASE64URL_NOPAD.encode(&client_data),
+            authenticator_data: BASE64URL_NOPAD.encode(&auth_data),
+            signature: BASE64URL_NOPAD.encode(&key.sign(&signed))
+        }
+    }
+
+    fn registered(key: &TestKey) -> StoredCredential {
+        let challenge = generate_challenge();
+        finish_registration(&challenge, &registration(key, &challenge, ORIGIN, RP_ID, FLAG_USER_PRESENT)).unwrap()
+    }
+
+    #[test]
+    fn registration_stores_p256_and_ed25519_credentials() {
+        setup();
+        for key in [TestKey::p256(), TestKey::ed25519()].iter() {
+            let stored = registered(key);
+            assert_eq!(stored.credential_id, BASE64URL_NOPAD.encode(b"test-credential"));
+            assert_eq!(decode("public_key", &stored.public_key).unwrap(), key.cose);
+            assert_eq!(stored.counter, 0);
+        }
+    }
+
+    #[test]
+    fn registration_rejects_other_challenge() {
+        setup();
+        let response = registration(&TestKey::p256(), &generate_challenge(), ORIGIN, RP_ID, FLAG_USER_PRESENT);
+        assert!(matches!(finish_registration(&generate_challenge(), &response), Err(WebauthnError::ChallengeMismatch())));
+    }
+
+    #[test]
+    fn registration_rejects_other_origin() {
+        setup();
+        let challenge = generate_challenge();
+        let response = registration(&TestKey::p256(), &challenge, "https://evil.test", RP_ID, FLAG_USER_PRESENT);
+        assert!(matches!(finish_registration(&challenge, &response), Err(WebauthnError::OriginMismatch())));
+    }
+
+    #[test]
+    fn registration_rejects_other_rp_id_hash() {
+        setup();
+        let challenge = generate_challenge();
+        let response = registration(&TestKey::p256(), &challenge, ORIGIN, "evil.test", FLAG_USER_PRESENT);
+        assert!(matches!(finish_registration(&challenge, &response), Err(WebauthnError::RpIdMismatch())));
+    }
+
+    #[test]
+    fn registration_requires_user_presence() {
+        setup();
+        let challenge = generate_challenge();
+        let response = registration(&TestKey::p256(), &challenge, ORIGIN, RP_ID, 0);
+        assert!(matches!(finish_registration(&challenge, &response), Err(WebauthnError::UserNotPresent())));
+    }
+
+    #[test]
+    fn assertion_verifies_p256_and_ed25519_signatures() {
+        setup();
+        for key in [TestKey::p256(), TestKey::ed25519()].iter() {
+            let stored = registered(key);
+            let challenge = generate_challenge();
+            let response = assertion(key, &stored, &challenge, ORIGIN, RP_ID, FLAG_USER_PRESENT, 1);
+            assert_eq!(finish_assertion(&challenge, &stored, &response).unwrap().counter, 1);
+        }
+    }
+
+    #[test]
+    fn assertion_rejects_other_challenge() {
+        setup();
+        let key = TestKey::p256();
+        let stored = registered(&key);
+        let response = assertion(&key, &stored, &generate_challenge(), ORIGIN, RP_ID, FLAG_USER_PRESENT, 1);
+        assert!(matches!(finish_assertion(&generate_challenge(), &stored, &response), Err(WebauthnError::ChallengeMismatch())));
+    }
+
+    #[test]
+    fn assertion_rejects_other_origin() {
+        setup();
+        let key = TestKey::p256();
+        let stored = registered(&key);
+        let challenge = generate_challenge();
+        let response = assertion(&key, &stored, &challenge, "https://evil.test", RP_ID, FLAG_USER_PRESENT, 1);
+        assert!(matches!(finish_assertion(&challenge, &stored, &response), Err(WebauthnError::OriginMismatch())));
+    }
+
+    #[test]
+    fn assertion_rejects_other_rp_id_hash() {
+        setup();
+        let key = TestKey::ed25519();
+        let stored = registered(&key);
+        let challenge = generate_challenge();
+        let response = assertion(&key, &stored, &challenge, ORIGIN, "evil.test", FLAG_USER_PRESENT, 1);
+        assert!(matches!(finish_assertion(&challenge, &stored, &response), Err(WebauthnError::RpIdMismatch())));
+    }
+
+    #[test]
+    fn assertion_requires_user_presence() {
+        setup();
+        let key = TestKey::p256();
+        let stored = registered(&key);
+        let challenge = generate_challenge();
+        let response = assertion(&key, &stored, &challenge, ORIGIN, RP_ID, 0, 1);
+        assert!(matches!(finish_assertion(&challenge, &stored, &response), Err(WebauthnError::UserNotPresent())));
+    }
+
+    #[test]
+    fn assertion_rejects_counter_regression() {
+        setup();
+        let key = TestKey::p256();
+        let stored = StoredCredential { counter: 5, ..registered(&key) };
+        let challenge = generate_challenge();
+        let response = assertion(&key, &stored, &challenge, ORIGIN, RP_ID, FLAG_USER_PRESENT, 5);
+        assert!(matches!(finish_assertion(&challenge, &stored, &response), Err(WebauthnError::CounterRegression())));
+    }
+
+    #[test]
+    fn assertion_rejects_signature_from_other_key() {
+        setup();
+        let stored = registered(&TestKey::p256());
+        let challenge = generate_challenge();
+        let response = assertion(&TestKey::p256(), &stored, &challenge, ORIGIN, RP_ID, FLAG_USER_PRESENT, 1);
+        assert!(matches!(finish_assertion(&challenge, &stored, &response), Err(WebauthnError::Crypto(_))));
+    }
+}